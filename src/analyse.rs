@@ -13,6 +13,7 @@ use std::f32::consts::PI;
 use std::sync::{Arc, RwLock};
 use std::rc::Rc;
 
+#[allow(clippy::too_many_arguments)]
 pub fn analyse_multithreaded(
             colours: &LoadedPalette, T: f32,
             cp_req_send: Sender<()>, cp_recv: Receiver<MultithreadedCacheProvider>,
@@ -55,6 +56,7 @@ pub fn analyse_multithreaded(
                w - 3, h - 2, TextAnchor::se(), font.as_ref(),
                palette.tl_rgb);
 
+    #[allow(clippy::type_complexity)]
     fn init_state<'a, T: GraphPixel>(
                 graph: &'a mut ImageGraph,
                 cp_req_send: Sender<()>, cp_recv: Receiver<MultithreadedCacheProvider>,
@@ -72,6 +74,7 @@ pub fn analyse_multithreaded(
             font.as_ref().clone()), cp_req_send, cp_recv, palette, ill, font
         )
     }
+    #[allow(clippy::type_complexity)]
     fn run_multithreaded<'a, T: GraphPixel+'a>(
                 mut state: (
                     Vec<Box<dyn FnOnce()+'a+Send>>,
@@ -111,6 +114,7 @@ pub fn analyse_multithreaded(
         state.0.push(Box::new(g));
         return state;
     }
+    #[allow(clippy::type_complexity)]
     fn run_all<'a, T: GraphPixel>(
                 state: (
                     Vec<Box<dyn FnOnce()+'a+Send>>,
@@ -194,6 +198,7 @@ pub fn analyse_singlethreaded<CP: CacheProvider, C: AsRef<RwLock<CP>>+Clone, FR:
     graph_rw.write().unwrap().save(fname).unwrap();
 }
 
+#[allow(clippy::type_complexity)]
 fn just_run<CP: CacheProvider, C: AsRef<RwLock<CP>>+Clone, FR: AsRef<Font>+Clone>(
             state: (Rc<RwLock<ImageGraph>>, C, Rc<Palette>, Rc<CAT16Illuminant>, FR),
             mut f: Box<dyn FnMut(Rc<RwLock<ImageGraph>>, C, Rc<Palette>, Rc<CAT16Illuminant>, FR)+Send>)
@@ -1,12 +1,13 @@
 use serde::{Serialize, Deserialize};
-use bincode;
 use directories::ProjectDirs;
 use crossbeam_channel::{Receiver, Sender};
 
 use crate::util::{Clip, CyclicClip, PackedF32, Lerp};
 use crate::colour::*;
+use crate::dither::ThresholdMatrix;
 
 use std::collections::HashMap;
+use std::path::PathBuf;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct PlotData<T: Copy> {
@@ -24,20 +25,36 @@ impl<T: Copy> PlotData<T> {
     }
 }
 
+/// The app cache directory; not necessarily existing yet.
+pub fn cache_dir() -> std::io::Result<PathBuf> {
+    use std::io::Error;
+    let dirs = ProjectDirs::from("app", "Quickmarble", "censor")
+        .ok_or(
+            Error::other("couldn't choose app cache directory")
+        )?;
+    return Ok(dirs.cache_dir().to_path_buf());
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct BigCacher {
     version: u64,
     plots: HashMap<(PackedF32, String), PlotData<CAM16UCS>>,
     spectra: HashMap<(PackedF32, PackedF32), Vec<CAM16UCS>>,
-    cam16_boundaries: HashMap<PackedF32, Vec<f32>>
+    cam16_boundaries: HashMap<PackedF32, Vec<f32>>,
+    bluenoise: HashMap<(usize, usize, u64), ThresholdMatrix>,
+    /// Lookup tables loaded this run; they are stored in their own files, not in the cache.
+    #[serde(skip)]
+    cam16_luts: HashMap<PackedF32, CAM16Lut>
 }
 impl BigCacher {
-    pub const VERSION: u64 = 2;
+    pub const VERSION: u64 = 6;
     pub fn new() -> Self {
         Self {
             plots: HashMap::new(),
             spectra: HashMap::new(),
             cam16_boundaries: HashMap::new(),
+            bluenoise: HashMap::new(),
+            cam16_luts: HashMap::new(),
             version: Self::VERSION
         }
     }
@@ -65,6 +82,53 @@ impl BigCacher {
         let k = PackedF32(T);
         self.cam16_boundaries.insert(k, boundary);
     }
+    pub fn get_bluenoise(&self, w: usize, h: usize, seed: u64) -> Option<&ThresholdMatrix> {
+        return self.bluenoise.get(&(w, h, seed));
+    }
+    pub fn set_bluenoise(&mut self, w: usize, h: usize, seed: u64, matrix: ThresholdMatrix) {
+        self.bluenoise.insert((w, h, seed), matrix);
+    }
+    pub fn get_cam16_lut(&mut self, T: f32) -> Option<&CAM16Lut> {
+        use std::collections::hash_map::Entry;
+        match self.cam16_luts.entry(PackedF32(T)) {
+            Entry::Occupied(entry) => { Some(entry.into_mut()) }
+            Entry::Vacant(entry) => { Some(entry.insert(Self::load_cam16_lut(T).ok()?)) }
+        }
+    }
+    pub fn set_cam16_lut(&mut self, T: f32, lut: CAM16Lut) {
+        // An unsaved table is only built again next time.
+        let _ = Self::save_cam16_lut(T, &lut);
+        self.cam16_luts.insert(PackedF32(T), lut);
+    }
+    /// Lookup tables are large, so every one is kept in its own file next to the cache
+    /// instead of being rewritten with it on every save.
+    fn cam16_lut_file(T: f32) -> std::io::Result<PathBuf> {
+        return Ok(cache_dir()?.join("luts").join(format!("{}.bin", T)));
+    }
+    pub fn load_cam16_lut(T: f32) -> std::io::Result<CAM16Lut> {
+        use std::io::Error;
+        let encoded = std::fs::read(Self::cam16_lut_file(T)?)?;
+        let (version, lut): (u64, CAM16Lut) = bincode::deserialize(encoded.as_slice())
+            .map_err(
+                |_| Error::other("couldn't decode lookup table")
+            )?;
+        if version != Self::VERSION {
+            return Err(Error::other("outdated lookup table"));
+        }
+        return Ok(lut);
+    }
+    pub fn save_cam16_lut(T: f32, lut: &CAM16Lut) -> std::io::Result<()> {
+        use std::io::Error;
+        let lut_file = Self::cam16_lut_file(T)?;
+        if let Some(dir) = lut_file.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let encoded = bincode::serialize(&(Self::VERSION, lut))
+            .map_err(
+                |_| Error::other("couldn't encode lookup table")
+            )?;
+        std::fs::write(lut_file, encoded)
+    }
     pub fn compute_cam16_boundary(ill: &CAT16Illuminant) -> Vec<f32> {
         use std::f32::consts::PI;
         let n = 400;
@@ -121,32 +185,24 @@ impl BigCacher {
         return data;
     }
     pub fn save(&self) -> std::io::Result<()> {
-        use std::io::{Error, ErrorKind};
-        let dirs = ProjectDirs::from("app", "Quickmarble", "censor")
-            .ok_or(
-                Error::new(ErrorKind::Other, "couldn't choose app cache directory")
-            )?;
-        let cache_path = dirs.cache_dir();
-        std::fs::create_dir_all(cache_path)?;
+        use std::io::Error;
+        let cache_path = cache_dir()?;
+        std::fs::create_dir_all(&cache_path)?;
         let cache_file = cache_path.join("cache.bin");
         let encoded = bincode::serialize(self)
             .map_err(
-                |_| Error::new(ErrorKind::Other, "couldn't encode cache")
+                |_| Error::other("couldn't encode cache")
             )?;
         std::fs::write(cache_file, encoded)
     }
     pub fn load() -> std::io::Result<Self> {
-        use std::io::{Error, ErrorKind};
-        let dirs = ProjectDirs::from("app", "Quickmarble", "censor")
-            .ok_or(
-                Error::new(ErrorKind::Other, "couldn't choose app cache directory")
-            )?;
-        let cache_path = dirs.cache_dir();
+        use std::io::Error;
+        let cache_path = cache_dir()?;
         let cache_file = cache_path.join("cache.bin");
         let encoded = std::fs::read(cache_file)?;
         let decoded: Self = bincode::deserialize(encoded.as_slice())
             .map_err(
-                |_| Error::new(ErrorKind::Other, "couldn't decode cache")
+                |_| Error::other("couldn't decode cache")
             )?;
         if decoded.version == Self::VERSION {
             return Ok(decoded);
@@ -174,17 +230,17 @@ pub trait CacheProvider {
     fn get_plot<F: Fn() -> PlotData<CAM16UCS>>(&mut self, key: &str, f: F) -> PlotData<CAM16UCS>;
     fn get_cam16_boundary(&mut self) -> Vec<f32>;
     fn get_spectrum(&mut self, ratio: f32) -> Vec<CAM16UCS>;
+    fn get_cam16_lut(&mut self) -> CAM16Lut;
     fn uncached(&self) -> NoCacheProvider;
 }
 
 #[derive(Clone)]
 pub struct NoCacheProvider {
-    T: f32,
     ill: CAT16Illuminant
 }
-impl<'a> NoCacheProvider {
-    pub fn new(T: f32, ill: CAT16Illuminant) -> Self {
-        Self { T, ill }
+impl NoCacheProvider {
+    pub fn new(ill: CAT16Illuminant) -> Self {
+        Self { ill }
     }
 }
 impl CacheProvider for NoCacheProvider {
@@ -197,6 +253,9 @@ impl CacheProvider for NoCacheProvider {
     fn get_spectrum(&mut self, ratio: f32) -> Vec<CAM16UCS> {
         return BigCacher::compute_spectrum(&self.ill, ratio);
     }
+    fn get_cam16_lut(&mut self) -> CAM16Lut {
+        return CAM16Lut::new(&self.ill);
+    }
     fn uncached(&self) -> NoCacheProvider {
         self.clone()
     }
@@ -243,8 +302,18 @@ impl<'a> CacheProvider for SinglethreadedCacheProvider<'a> {
             }
         }
     }
+    fn get_cam16_lut(&mut self) -> CAM16Lut {
+        match self.cacher.get_cam16_lut(self.T) {
+            Some(data) => { data.clone() }
+            None => {
+                let data = CAM16Lut::new(self.ill);
+                self.cacher.set_cam16_lut(self.T, data.clone());
+                return data;
+            }
+        }
+    }
     fn uncached(&self) -> NoCacheProvider {
-        NoCacheProvider::new(self.T, self.ill.clone())
+        NoCacheProvider::new(*self.ill)
     }
 }
 
@@ -254,14 +323,17 @@ pub enum CacheRequest {
     CAM16BoundaryState { T: f32 },
     CAM16BoundaryWrite { T: f32, data: Vec<f32> },
     SpectrumState { T: f32, ratio: f32 },
-    SpectrumWrite { T: f32, ratio: f32, data: Vec<CAM16UCS> }
+    SpectrumWrite { T: f32, ratio: f32, data: Vec<CAM16UCS> },
+    CAM16LutState { T: f32 },
+    CAM16LutWrite { T: f32, data: CAM16Lut }
 }
 unsafe impl Send for CacheRequest {}
 
 pub enum CacheResponse {
     Plot(Option<PlotData<CAM16UCS>>),
     CAM16Boundary(Option<Vec<f32>>),
-    Spectrum(Option<Vec<CAM16UCS>>)
+    Spectrum(Option<Vec<CAM16UCS>>),
+    CAM16Lut(Option<CAM16Lut>)
 }
 unsafe impl Send for CacheResponse {}
 
@@ -332,8 +404,24 @@ impl CacheProvider for MultithreadedCacheProvider {
             Err(_) => { panic!("The cache is dead!") }
         }
     }
+    fn get_cam16_lut(&mut self) -> CAM16Lut {
+        self.sender.send(CacheRequest::CAM16LutState { T: self.T }).unwrap();
+        match self.receiver.recv() {
+            Ok(CacheResponse::CAM16Lut(Some(data))) => { data }
+            Ok(CacheResponse::CAM16Lut(None)) => {
+                let data = CAM16Lut::new(&self.ill);
+                self.sender.send(CacheRequest::CAM16LutWrite {
+                    T: self.T,
+                    data: data.clone()
+                }).unwrap();
+                return data;
+            }
+            Ok(_) => { panic!("I never asked for this") }
+            Err(_) => { panic!("The cache is dead!") }
+        }
+    }
     fn uncached(&self) -> NoCacheProvider {
-        NoCacheProvider::new(self.T, self.ill.clone())
+        NoCacheProvider::new(self.ill)
     }
 }
 
@@ -352,7 +440,7 @@ impl<'a> CacheHoster<'a> {
         return (resp_recv, req_send);
     }
     pub fn process(&mut self) {
-        while self.connections.len() > 0 {
+        while !self.connections.is_empty() {
             let mut select = crossbeam_channel::Select::new();
             for (recv, _) in self.connections.iter() {
                 select.recv(recv);
@@ -384,6 +472,14 @@ impl<'a> CacheHoster<'a> {
                 Ok(CacheRequest::SpectrumWrite { T, ratio, data }) => {
                     self.cacher.set_spectrum(T, ratio, data);
                 }
+                Ok(CacheRequest::CAM16LutState { T }) => {
+                    self.connections[i].1.send(
+                        CacheResponse::CAM16Lut(self.cacher.get_cam16_lut(T).cloned())
+                    ).unwrap();
+                }
+                Ok(CacheRequest::CAM16LutWrite { T, data }) => {
+                    self.cacher.set_cam16_lut(T, data);
+                }
                 Err(_) => {
                     self.connections.remove(i);
                 }
@@ -29,6 +29,10 @@ impl RGB255 {
     pub fn new(r: u8, g: u8, b: u8) -> Self {
         Self { r, g, b }
     }
+    /// Lowercase `rrggbb`, as read by `parse_hex`.
+    pub fn hex(&self) -> String {
+        format!("{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+    }
 }
 
 impl From<RGB255> for Rgb<u8> {
@@ -37,7 +41,7 @@ impl From<RGB255> for Rgb<u8> {
     }
 }
 
-fn ungamma(x: f32) -> f32 {
+pub fn ungamma(x: f32) -> f32 {
     if x <= 0.04045 {
         25. * x / 323.
     } else {
@@ -45,6 +49,14 @@ fn ungamma(x: f32) -> f32 {
     }
 }
 
+pub fn gamma(x: f32) -> f32 {
+    if x <= 0.0031308 {
+        323. * x / 25.
+    } else {
+        (211. * x.powf(5. / 12.) - 11.) / 200.
+    }
+}
+
 #[derive(Clone, Copy, PartialEq)]
 pub struct RGB1 {
     pub r: f32,
@@ -60,6 +72,15 @@ impl From<RGB255> for RGB1 {
         }
     }
 }
+impl From<RGB1> for RGB255 {
+    fn from(c: RGB1) -> Self {
+        Self {
+            r: (c.r * 255.).round().clip(0., 255.) as u8,
+            g: (c.g * 255.).round().clip(0., 255.) as u8,
+            b: (c.b * 255.).round().clip(0., 255.) as u8
+        }
+    }
+}
 impl Vector for RGB1 {
     fn dist(x: &Self, y: &Self) -> f32 {
         f32::sqrt(
@@ -98,6 +119,20 @@ impl From<RGB255> for CIEXYZ {
         Self::from(RGB1::from(c))
     }
 }
+/// Out of gamut colours are clipped to the sRGB cube in linear light.
+impl From<CIEXYZ> for RGB1 {
+    fn from(c: CIEXYZ) -> Self {
+        let (X, Y, Z) = (c.X / 100., c.Y / 100., c.Z / 100.);
+        let r =  3.2406 * X - 1.5372 * Y - 0.4986 * Z;
+        let g = -0.9689 * X + 1.8758 * Y + 0.0415 * Z;
+        let b =  0.0557 * X - 0.2040 * Y + 1.0570 * Z;
+        Self {
+            r: gamma(r.clip(0., 1.)),
+            g: gamma(g.clip(0., 1.)),
+            b: gamma(b.clip(0., 1.))
+        }
+    }
+}
 impl From<Wavelength> for CIEXYZ {
     fn from(c: Wavelength) -> Self {
         let wl = c.wl as f64;
@@ -598,3 +633,87 @@ impl MetricSpace for CAM16UCS {
         CAM16UCS::dist(self, other)
     }
 }
+
+/// sRGB to CAM16UCS lookup table for one illuminant.
+///
+/// Nodes of each channel are spaced quadratically, densest near zero where the transform
+/// is the steepest; values between them are interpolated trilinearly.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CAM16Lut {
+    nodes: Vec<CAM16UCS>
+}
+impl CAM16Lut {
+    const SIZE: usize = 65;
+
+    fn position(i: usize) -> f32 {
+        (i as f32 / (Self::SIZE - 1) as f32).powi(2)
+    }
+    pub fn new(ill: &CAT16Illuminant) -> Self {
+        let n = Self::SIZE;
+        let mut nodes = Vec::with_capacity(n * n * n);
+        for r in 0..n {
+            for g in 0..n {
+                for b in 0..n {
+                    let c = RGB1 {
+                        r: Self::position(r),
+                        g: Self::position(g),
+                        b: Self::position(b)
+                    };
+                    nodes.push(CAM16UCS::of(CIEXYZ::from(c), ill));
+                }
+            }
+        }
+        Self { nodes }
+    }
+    fn node(&self, r: usize, g: usize, b: usize) -> CAM16UCS {
+        self.nodes[(r * Self::SIZE + g) * Self::SIZE + b]
+    }
+    pub fn of(&self, c: RGB1) -> CAM16UCS {
+        let split = |x: f32| {
+            let x = x.clip(0., 1.);
+            let i = usize::min((x.sqrt() * (Self::SIZE - 1) as f32) as usize, Self::SIZE - 2);
+            let (x0, x1) = (Self::position(i), Self::position(i + 1));
+            (i, ((x - x0) / (x1 - x0)).clip(0., 1.))
+        };
+        let (r, fr) = split(c.r);
+        let (g, fg) = split(c.g);
+        let (b, fb) = split(c.b);
+        let lerp_b = |r, g| CAM16UCS::mix(self.node(r, g, b), self.node(r, g, b + 1), fb);
+        let lerp_g = |r| CAM16UCS::mix(lerp_b(r, g), lerp_b(r, g + 1), fg);
+        return CAM16UCS::mix(lerp_g(r), lerp_g(r + 1), fr);
+    }
+    /// Mean and maximal distance to the exact transform
+    /// over every `stride`-th value of each channel.
+    pub fn error(&self, ill: &CAT16Illuminant, stride: usize) -> (f32, f32) {
+        let mut sum = 0.;
+        let mut max: f32 = 0.;
+        let mut count = 0;
+        for r in (0..=255).step_by(stride) {
+            for g in (0..=255).step_by(stride) {
+                for b in (0..=255).step_by(stride) {
+                    let rgb = RGB255::new(r as u8, g as u8, b as u8);
+                    let exact = CAM16UCS::of(CIEXYZ::from(rgb), ill);
+                    let d = CAM16UCS::dist(&exact, &self.of(RGB1::from(rgb)));
+                    sum += d;
+                    max = f32::max(max, d);
+                    count += 1;
+                }
+            }
+        }
+        return (sum / count as f32, max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cam16_lut_accuracy() {
+        let ill = CAT16Illuminant::new(CIExy::from_T(5500.));
+        let lut = CAM16Lut::new(&ill);
+        let (mean, max) = lut.error(&ill, 3);
+        assert!(mean < 0.02, "mean error {}", mean);
+        assert!(max < 1.0, "maximal error {}", max);
+    }
+}
@@ -0,0 +1,1266 @@
+use image::{RgbImage, RgbaImage, Rgba};
+use img_parts::{png::Png, ImageICC};
+use text_io::scan;
+
+use crate::colour::*;
+use crate::palette::*;
+use crate::loader::*;
+use crate::dither::*;
+use crate::cache::{PlotData, BigCacher};
+use crate::indexed::{IndexedImage, save_animated_gif, save_apng};
+use crate::graph::ImageGraph;
+use crate::text::{Font, TextAnchor};
+use crate::metadata;
+use crate::util::Clip;
+use crate::constrained::{HardwareTarget, ConstrainedDither};
+use crate::resize::{resize_area, scale_nearest};
+use crate::subset::{ColourHistogram, PaletteSubset};
+use crate::usage::PaletteUsage;
+use crate::remap::{PaletteMapping, Recolourer};
+use crate::coverage::Coverage;
+use crate::valuecheck::{ValueCheck, ValueCheckFrame};
+use crate::shading::{Light, LightModel, ShadedPalette};
+use crate::exporter::{
+    PaletteFormat, PaletteOrder, ExportError, export_palette, dedup_colours, sort_colours
+};
+use crate::widget::{Widget, UsageHistogramWidget, ShadingGridWidget};
+use crate::cache::NoCacheProvider;
+
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::sync::RwLock;
+
+pub fn temperature_from_cmd<'a>(matches: &clap::ArgMatches<'a>) -> Result<f32, String> {
+    if let Some(D) = matches.value_of("D") {
+        match D {
+            "50" => { Ok(5000.00) }
+            "55" => { Ok(5500.00) }
+            "65" => { Ok(6503.51) }
+            _ => { Err(format!("Invalid illuminant preset: D{}", D)) }
+        }
+    } else {
+        str::parse(matches.value_of("T").unwrap_or("5500"))
+            .map_err(|e| format!("Error parsing temperature: {}", e))
+    }
+}
+
+pub fn alpha_from_cmd<'a>(matches: &clap::ArgMatches<'a>) -> Result<AlphaOptions, String> {
+    let mut alpha = AlphaOptions::default();
+    if let Some(threshold) = matches.value_of("alpha_threshold") {
+        alpha.threshold = str::parse(threshold)
+            .map_err(|e| format!("Error parsing alpha threshold: {}", e))?;
+    }
+    if let Some(background) = matches.value_of("background") {
+        let c = parse_hex(background.into())
+            .map_err(|e| format!("Error parsing background colour: {}", e))?;
+        alpha.background = Some(c);
+    }
+    return Ok(alpha);
+}
+
+pub fn palette_from_cmd<'a>(matches: &clap::ArgMatches<'a>, verbose: bool)
+            -> Result<LoadedPalette, String> {
+    let alpha = alpha_from_cmd(matches)?;
+    let result = if let Some(hex_list) = matches.value_of("colours") {
+        let hex_list = hex_list.split(',')
+            .map(|s| String::from(s))
+            .collect::<Vec<_>>();
+        load_from_hex(&hex_list)
+    } else if let Some(filename) = matches.value_of("hexfile") {
+        load_from_file(filename.into())
+    } else if let Some(slug) = matches.value_of("lospec") {
+        let base_url = matches.value_of("lospec_url").unwrap_or(LOSPEC_URL);
+        let offline = matches.is_present("offline");
+        if verbose { eprintln!("Loading palette from {}...", base_url); }
+        load_from_lospec(slug.into(), base_url, offline)
+    } else if let Some(filename) = matches.value_of("imagefile") {
+        load_from_image(filename.into(), alpha)
+    } else if let Some(filename) = matches.value_of("swatchfile") {
+        load_from_swatches(filename.into(), alpha)
+    } else if let Some(filename) = matches.value_of("indexedfile") {
+        load_from_colour_table(filename.into())
+    } else {
+        return Err("Impossible happened! Blame the `clap` library. Report this error.".into());
+    };
+    let palette = match result {
+        Ok(x) => { x }
+        Err(e) => {
+            return Err(format!("Error while getting palette: {}", e));
+        }
+    };
+    if palette.colours.is_empty() {
+        return Err(format!("Error while validating palette: {}",
+                           PaletteCheckError::TooFewColours(0)));
+    }
+    return Ok(palette);
+}
+
+pub fn dither_method_from_cmd<'a>(matches: &clap::ArgMatches<'a>) -> Result<DitheringMethod, String> {
+    let nodither_provided = matches.is_present("nodither");
+    let bayer_provided = matches.is_present("bayer");
+    let whitenoise_provided = matches.is_present("whitenoise");
+    let bluenoise_provided = matches.is_present("bluenoise");
+    let pattern_provided = matches.is_present("pattern");
+    let dispersed_provided = matches.is_present("dispersed");
+    let halftone_provided = matches.is_present("halftone");
+    let lines_provided = matches.is_present("lines");
+    let crosshatch_provided = matches.is_present("crosshatch");
+    let ign_provided = matches.is_present("ign");
+    let threshold_map_provided = matches.is_present("threshold_map");
+    let seed: Option<u64> = match matches.value_of("seed") {
+        Some(s) => {
+            Some(str::parse(s).map_err(|e| format!("Could not parse seed: {}", e))?)
+        }
+        None => { None }
+    };
+    let kernels = [
+        ("floyd_steinberg", DiffusionKernel::FloydSteinberg),
+        ("atkinson", DiffusionKernel::Atkinson),
+        ("jarvis", DiffusionKernel::JarvisJudiceNinke),
+        ("stucki", DiffusionKernel::Stucki),
+        ("sierra", DiffusionKernel::Sierra),
+        ("burkes", DiffusionKernel::Burkes)
+    ];
+    let kernel = kernels.iter()
+        .find(|(name, _)| matches.is_present(name))
+        .map(|&(_, kernel)| kernel);
+
+    let method = match () {
+        () if nodither_provided => { DitheringMethod::None }
+        () if bayer_provided => {
+            let n = str::parse(matches.value_of("bayer").unwrap())
+                .map_err(|e| format!("Could not parse Bayer matrix size: {}", e))?;
+            DitheringMethod::Bayer(n)
+        }
+        () if whitenoise_provided => {
+            let wxh = matches.value_of("whitenoise").unwrap();
+            let w: usize;
+            let h: usize;
+            scan!(wxh.bytes() => "{}x{}", w, h);
+            if w == 0 || h == 0 {
+                return Err("White noise matrix sides must be at least 1".into());
+            }
+            DitheringMethod::WhiteNoise(w, h, rand::random())
+        }
+        () if bluenoise_provided => {
+            let wxh = matches.value_of("bluenoise").unwrap();
+            let w: usize;
+            let h: usize;
+            scan!(wxh.bytes() => "{}x{}", w, h);
+            if w == 0 || h == 0 {
+                return Err("Blue noise matrix sides must be at least 1".into());
+            }
+            DitheringMethod::BlueNoise(w, h, BLUENOISE_SEED)
+        }
+        () if dispersed_provided => {
+            let n = str::parse(matches.value_of("dispersed").unwrap())
+                .map_err(|e| format!("Could not parse dispersed matrix size: {}", e))?;
+            if n == 0 {
+                return Err("Dispersed matrix size must be at least 1".into());
+            }
+            DitheringMethod::Dispersed(n)
+        }
+        () if halftone_provided => {
+            let n = str::parse(matches.value_of("halftone").unwrap())
+                .map_err(|e| format!("Could not parse halftone cell size: {}", e))?;
+            if n == 0 {
+                return Err("Halftone cell size must be at least 1".into());
+            }
+            DitheringMethod::Halftone(n)
+        }
+        () if lines_provided => {
+            let n = str::parse(matches.value_of("lines").unwrap())
+                .map_err(|e| format!("Could not parse line period: {}", e))?;
+            if n == 0 {
+                return Err("Line period must be at least 1".into());
+            }
+            let direction = LineDirection::from_name(
+                matches.value_of("line_direction").unwrap_or("horizontal")
+            ).unwrap();
+            DitheringMethod::Lines(n, direction)
+        }
+        () if crosshatch_provided => {
+            let n = str::parse(matches.value_of("crosshatch").unwrap())
+                .map_err(|e| format!("Could not parse cross-hatch period: {}", e))?;
+            if n == 0 {
+                return Err("Cross-hatch period must be at least 1".into());
+            }
+            DitheringMethod::CrossHatch(n)
+        }
+        () if ign_provided => {
+            let wxh = matches.value_of("ign").unwrap();
+            let w: usize;
+            let h: usize;
+            scan!(wxh.bytes() => "{}x{}", w, h);
+            if w == 0 || h == 0 {
+                return Err("Interleaved gradient noise matrix sides must be at least 1".into());
+            }
+            DitheringMethod::InterleavedGradient(w, h)
+        }
+        () if threshold_map_provided => {
+            let filename = matches.value_of("threshold_map").unwrap();
+            let matrix = load_threshold_map(filename)
+                .map_err(|e| format!("Could not load threshold map: {}", e))?;
+            DitheringMethod::Custom(matrix)
+        }
+        () if pattern_provided => {
+            let n = str::parse(matches.value_of("pattern").unwrap())
+                .map_err(|e| format!("Could not parse pattern matrix size: {}", e))?;
+            DitheringMethod::Pattern(n)
+        }
+        () if kernel.is_some() => {
+            let serpentine = matches.is_present("serpentine");
+            let strength = str::parse(matches.value_of("strength").unwrap_or("1"))
+                .map_err(|e| format!("Could not parse diffusion strength: {}", e))?;
+            DitheringMethod::ErrorDiffusion { kernel: kernel.unwrap(), serpentine, strength }
+        }
+        () => { DitheringMethod::default() }
+    };
+    let method = match seed {
+        Some(seed) => { method.with_seed(seed) }
+        None => { method }
+    };
+    return Ok(method);
+}
+
+/// Settings of the `dither` subcommand shared by all input images.
+struct DitherSetup {
+    ill: CAT16Illuminant,
+    colours: Vec<RGB255>,
+    palette: Palette,
+    lut: CAM16Lut,
+    alpha: AlphaOptions,
+    method: DitheringMethod,
+    matrix: Option<ThresholdMatrix>,
+    spread: Spread,
+    threads: usize,
+    size: Option<(usize, usize)>,
+    scale: usize,
+    subset: Option<usize>,
+    batch: bool
+}
+
+/// Runs the `dither` subcommand, returning the report for the user:
+/// the chosen subset indices or the comparison metrics.
+pub fn dither<'a>(matches: &clap::ArgMatches<'a>, cacher: &mut BigCacher,
+                  verbose: bool) -> Result<String, String> {
+    let T = temperature_from_cmd(matches)?;
+    let ill = CAT16Illuminant::new(CIExy::from_T(T));
+
+    let colours = palette_from_cmd(matches, verbose)?.colours;
+    let palette = Palette::new(colours.clone(), &ill, false);
+
+    let alpha = alpha_from_cmd(matches)?;
+    let lut = cam16_lut(T, &ill, verbose);
+    let inputs = image_inputs_from_cmd(matches)?;
+    if matches.is_present("compare") {
+        if inputs.len() > 1 {
+            return Err("Comparison sheets are made for one image at a time".into());
+        }
+        let outfile = output_name(matches.value_of("outfile").unwrap_or("plot.png"));
+        return dither_compare(matches, &inputs[0], &outfile, &palette, &ill, &lut, alpha,
+                              cacher, verbose);
+    }
+
+    let method = dither_method_from_cmd(matches)?;
+    let matrix = Ditherer::threshold_matrix(&method, cacher, verbose);
+    let palette_n = palette.n;
+    let setup = DitherSetup {
+        ill, colours, palette, lut, alpha, method, matrix,
+        spread: spread_from_cmd(matches)?,
+        threads: threads_from_cmd(matches)?,
+        size: resize_from_cmd(matches)?,
+        scale: scale_from_cmd(matches)?,
+        subset: subset_from_cmd(matches, palette_n)?,
+        batch: inputs.len() > 1
+    };
+    let named = [("usage", "Usage reports"), ("value_check", "Value checks"),
+                 ("assignment", "Block assignments")];
+    for (arg, what) in named.iter() {
+        if let Some(filename) = matches.value_of(arg) {
+            if setup.batch && !filename.contains("{name}") {
+                return Err(format!("{} of several images require {{name}} in the file name", what));
+            }
+        }
+    }
+
+    let output_dir = match matches.value_of("output_dir") {
+        Some(x) => { x }
+        None => {
+            if inputs.len() > 1 {
+                return Err("Dithering several images requires --output-dir".into());
+            }
+            let outfile = output_name(matches.value_of("outfile").unwrap_or("plot.png"));
+            return dither_file(matches, &setup, &inputs[0], &outfile, verbose);
+        }
+    };
+    std::fs::create_dir_all(output_dir)
+        .map_err(|e| format!("Error creating output directory: {}", e))?;
+    let template = matches.value_of("name_template").unwrap_or("{name}.png");
+    let outfiles: Vec<String> = inputs.iter()
+        .map(|input| {
+            let name = template.replace("{name}", &file_stem(input));
+            output_name(&std::path::Path::new(output_dir).join(name).to_string_lossy())
+        })
+        .collect();
+    let mut written: HashMap<std::path::PathBuf, &str> = HashMap::new();
+    for (input, outfile) in inputs.iter().zip(outfiles.iter()) {
+        for file in dither_outputs(matches, input, outfile) {
+            match written.insert(file.clone().into(), input) {
+                Some(other) if other == input.as_str() => {
+                    return Err(format!("{} would be written to {} twice", input, file));
+                }
+                Some(other) => {
+                    return Err(format!("{} and {} would both be written to {}", other, input, file));
+                }
+                None => {}
+            }
+        }
+    }
+    let mut failed = 0;
+    let mut report = String::new();
+    for (input, outfile) in inputs.iter().zip(outfiles.iter()) {
+        if verbose { eprintln!("Dithering {} into {}...", input, outfile) }
+        match dither_file(matches, &setup, input, outfile, verbose) {
+            Ok(lines) => { report += &lines; }
+            Err(e) => {
+                eprintln!("{}: {}", input, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(format!("{} of {} images failed", failed, inputs.len()));
+    }
+    return Ok(report);
+}
+
+/// Every file dithering the image writes, the result first.
+fn dither_outputs<'a>(matches: &clap::ArgMatches<'a>, image_filename: &str, outfile: &str)
+            -> Vec<String> {
+    let mut files = vec![outfile.to_string()];
+    if matches.is_present("hardware") {
+        files.push(assignment_name(matches, image_filename, outfile));
+    }
+    files.extend(named_file(matches, "usage", image_filename));
+    files.extend(named_file(matches, "value_check", image_filename).map(|f| output_name(&f)));
+    return files;
+}
+
+/// File given with the option, `{name}` replaced with the input file name.
+fn named_file<'a>(matches: &clap::ArgMatches<'a>, arg: &str, image_filename: &str)
+            -> Option<String> {
+    matches.value_of(arg).map(|f| f.replace("{name}", &file_stem(image_filename)))
+}
+
+/// Block assignment file: the given one or the output file with the `.json` extension.
+fn assignment_name<'a>(matches: &clap::ArgMatches<'a>, image_filename: &str, outfile: &str)
+            -> String {
+    match named_file(matches, "assignment", image_filename) {
+        Some(x) => { x }
+        None => {
+            std::path::Path::new(outfile).with_extension("json")
+                .to_string_lossy().into_owned()
+        }
+    }
+}
+
+/// Output file name with `.png` appended unless it is a PNG or GIF one.
+fn output_name(outfile: &str) -> String {
+    if outfile.ends_with(".png") || outfile.ends_with(".gif") {
+        return outfile.into();
+    }
+    return format!("{}.png", outfile);
+}
+
+/// File name without directories and extension.
+fn file_stem(filename: &str) -> String {
+    std::path::Path::new(filename).file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Image extensions picked up from input directories.
+const IMAGE_EXTENSIONS: [&str; 13] = [
+    "png", "apng", "gif", "jpg", "jpeg", "bmp", "tga", "tif", "tiff", "webp", "ppm", "pgm", "pnm"
+];
+
+/// Expands the input images: directories into the images inside them and glob patterns
+/// into the matching files, both in name order. Other inputs are kept as is.
+fn image_inputs_from_cmd<'a>(matches: &clap::ArgMatches<'a>) -> Result<Vec<String>, String> {
+    let mut inputs = vec![];
+    for value in matches.values_of("imageinput").unwrap() {
+        let path = std::path::Path::new(value);
+        let mut found: Vec<String> = if path.is_dir() {
+            let entries = std::fs::read_dir(path)
+                .map_err(|e| format!("Error reading input directory {}: {}", value, e))?;
+            entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| {
+                    path.is_file() && path.extension()
+                        .map(|ext| ext.to_string_lossy().to_lowercase())
+                        .is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.as_str()))
+                })
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect()
+        } else if value.contains(['*', '?', '[']) {
+            let paths = glob::glob(value)
+                .map_err(|e| format!("Invalid input pattern {}: {}", value, e))?;
+            paths
+                .filter_map(|path| path.ok())
+                .filter(|path| path.is_file())
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect()
+        } else {
+            vec![value.into()]
+        };
+        if found.is_empty() {
+            return Err(format!("No input images found in {}", value));
+        }
+        found.sort();
+        inputs.extend(found);
+    }
+    return Ok(inputs);
+}
+
+/// Dithers one input image (or animation) into `outfile`, returning the subset report.
+fn dither_file<'a>(matches: &clap::ArgMatches<'a>, setup: &DitherSetup,
+                   image_filename: &str, outfile: &str, verbose: bool) -> Result<String, String> {
+    let gif_output = outfile.ends_with(".gif");
+    let transparent = matches.is_present("transparent");
+    let paletted = matches.is_present("paletted") || gif_output;
+    let palette = &setup.palette;
+
+    if is_animation(image_filename) {
+        if matches.is_present("hardware") {
+            return Err("Hardware constraints are only supported for still images".into());
+        }
+        return dither_animation(matches, setup, image_filename, outfile, gif_output, verbose);
+    }
+    let (source, icc_profile) = {
+        let image = load_image(image_filename.into(), setup.alpha)
+            .map_err(|e| format!("Error loading input image: {}", e))?;
+        (resize_image(&image.data, setup.size), image.icc_profile)
+    };
+    let (subset, report) = subset_palette(setup, &[&source], image_filename, verbose);
+    let palette = subset.as_ref().unwrap_or(palette);
+
+    let dithered = match matches.value_of("hardware") {
+        Some(name) => {
+            if verbose { eprintln!("Converting the image into CAM16UCS...") }
+            let plot = image_to_cam16(&source, &setup.lut);
+            let target = HardwareTarget::from_name(name).unwrap();
+            let fixed = subpalettes_from_cmd(matches, palette.n)?;
+            let result = ConstrainedDither::dither(plot, palette, &setup.ill,
+                                                   target.constraint(), fixed, &setup.method,
+                                                   setup.spread, setup.matrix.as_ref(), verbose);
+            let assignment_file = assignment_name(matches, image_filename, outfile);
+            std::fs::write(&assignment_file, result.to_json(target).pretty(2))
+                .map_err(|e| format!("Error saving block assignment: {}", e))?;
+            result.image
+        }
+        None => {
+            Ditherer::dither_parallel(&source, &setup.lut, palette, &setup.method,
+                                      setup.matrix.as_ref(), setup.spread, setup.threads,
+                                      verbose)
+        }
+    };
+    if let Some(usage_file) = named_file(matches, "usage", image_filename) {
+        let usage = PaletteUsage::new(&[&dithered.data], &setup.palette, &setup.lut);
+        write_usage(&usage, &setup.palette, &usage_file)?;
+    }
+    if let Some(value_file) = named_file(matches, "value_check", image_filename) {
+        let value_file = output_name(&value_file);
+        write_value_check(&[(&source, &dithered)], &setup.lut, setup.scale, &value_file, verbose)?;
+    }
+    let scale = setup.scale;
+    let dithered = if scale > 1 { scale_nearest(&dithered, scale) } else { dithered };
+    let h = dithered.data.len() as u32;
+    let w = dithered.data[0].len() as u32;
+
+    let result = if paletted {
+        IndexedImage::from_plot(&dithered, &setup.colours, transparent)
+            .and_then(|image| {
+                if gif_output { image.save_gif(outfile) } else { image.save_png(outfile) }
+            })
+            .map_err(|e| e.to_string())
+    } else if transparent {
+        let mut image = RgbaImage::new(w, h);
+        for y in 0..h {
+            for x in 0..w {
+                if let Some(rgb) = dithered.data[y as usize][x as usize] {
+                    image.put_pixel(x, y, Rgba([rgb.r, rgb.g, rgb.b, 0xff]));
+                }
+            }
+        }
+        image.save(outfile).map_err(|e| e.to_string())
+    } else {
+        let mut image = RgbImage::new(w, h);
+        for y in 0..h {
+            for x in 0..w {
+                if let Some(rgb) = dithered.data[y as usize][x as usize] {
+                    image.put_pixel(x, y, rgb.into());
+                }
+            }
+        }
+        image.save(outfile).map_err(|e| e.to_string())
+    };
+    if let Err(e) = result {
+        return Err(format!("Error saving output image: {}", e));
+    }
+
+    if gif_output { return Ok(report); }
+    if let Some(ref icc_profile) = icc_profile {
+        write_icc_profile(outfile, icc_profile);
+    }
+    return Ok(report);
+}
+
+/// Writes the lightness of the sources and results side by side with their difference.
+fn write_value_check(frames: &[ValueCheckFrame], lut: &CAM16Lut, scale: usize,
+                     outfile: &str, verbose: bool) -> Result<(), String> {
+    let (preview, mean) = ValueCheck::new(lut).render(frames);
+    if verbose { eprintln!("Mean lightness difference: {:.2}", mean) }
+    let preview = if scale > 1 { scale_nearest(&preview, scale) } else { preview };
+    let h = preview.data.len() as u32;
+    let w = if h > 0 { preview.data[0].len() as u32 } else { 0 };
+    let mut image = RgbImage::new(w, h);
+    for y in 0..h {
+        for x in 0..w {
+            if let Some(rgb) = preview.data[y as usize][x as usize] {
+                image.put_pixel(x, y, rgb.into());
+            }
+        }
+    }
+    image.save(outfile).map_err(|e| format!("Error saving value check: {}", e))
+}
+
+fn image_to_cam16(data: &Vec<Vec<Option<RGB1>>>, lut: &CAM16Lut) -> PlotData<CAM16UCS> {
+    let image_cam16: Vec<Vec<Option<CAM16UCS>>> = data.iter().map(
+        |row| row.iter().map(
+            |opt| opt.map(
+                |rgb| lut.of(rgb)
+            )
+        ).collect()
+    ).collect();
+    return PlotData::new(image_cam16);
+}
+
+/// The sRGB to CAM16UCS lookup table for the illuminant, created and cached if missing.
+fn cam16_lut(T: f32, ill: &CAT16Illuminant, verbose: bool) -> CAM16Lut {
+    if let Ok(lut) = BigCacher::load_cam16_lut(T) {
+        return lut;
+    }
+    if verbose { eprintln!("Creating sRGB to CAM16UCS lookup table ({}K)...", T) }
+    let lut = CAM16Lut::new(ill);
+    if verbose {
+        let (mean, max) = lut.error(ill, 5);
+        eprintln!("Lookup table error: mean {:.4}, max {:.4}", mean, max);
+    }
+    if let Err(e) = BigCacher::save_cam16_lut(T, &lut) {
+        if verbose { eprintln!("Error saving lookup table: {}", e); }
+    }
+    return lut;
+}
+
+/// Ordered dithering perturbation from `--chromatic` and `--spread`.
+fn spread_from_cmd<'a>(matches: &clap::ArgMatches<'a>) -> Result<Spread, String> {
+    let k = str::parse(matches.value_of("spread").unwrap_or("1"))
+        .map_err(|e| format!("Could not parse spread: {}", e))?;
+    if matches.is_present("chromatic") {
+        return Ok(Spread::Chromatic(k));
+    }
+    return Ok(Spread::Lightness(k));
+}
+
+/// Parses the `--resize` size.
+fn resize_from_cmd<'a>(matches: &clap::ArgMatches<'a>) -> Result<Option<(usize, usize)>, String> {
+    let wxh = match matches.value_of("resize") {
+        Some(x) => { x }
+        None => { return Ok(None); }
+    };
+    let w: usize;
+    let h: usize;
+    scan!(wxh.bytes() => "{}x{}", w, h);
+    if w == 0 || h == 0 {
+        return Err("Resized image must not be empty".into());
+    }
+    return Ok(Some((w, h)));
+}
+
+/// Parses the `--scale` factor, 1 by default.
+fn scale_from_cmd<'a>(matches: &clap::ArgMatches<'a>) -> Result<usize, String> {
+    let scale = str::parse(matches.value_of("scale").unwrap_or("1"))
+        .map_err(|e| format!("Could not parse output scale: {}", e))?;
+    if scale == 0 {
+        return Err("Output scale must be at least 1".into());
+    }
+    return Ok(scale);
+}
+
+/// Parses `--subset`, which must not exceed the palette size.
+fn subset_from_cmd<'a>(matches: &clap::ArgMatches<'a>, n: usize) -> Result<Option<usize>, String> {
+    let k: usize = match matches.value_of("subset") {
+        Some(x) => {
+            str::parse(x).map_err(|e| format!("Could not parse subset size: {}", e))?
+        }
+        None => { return Ok(None); }
+    };
+    if k == 0 || k > n {
+        return Err(format!("Subset size must be between 1 and the palette size ({})", n));
+    }
+    return Ok(Some(k));
+}
+
+/// With `--subset`, chooses the palette colours serving the images best;
+/// also returns the line listing their indices (prefixed with the file name in batches).
+fn subset_palette(setup: &DitherSetup, images: &[&Vec<Vec<Option<RGB1>>>],
+                  image_filename: &str, verbose: bool) -> (Option<Palette>, String) {
+    let k = match setup.subset {
+        Some(k) => { k }
+        None => { return (None, String::new()); }
+    };
+    if verbose { eprintln!("Choosing {} of {} palette colours...", k, setup.palette.n) }
+    let mut histogram = ColourHistogram::default();
+    for image in images.iter() {
+        histogram.add(image, &setup.lut);
+    }
+    let indices = PaletteSubset::choose(&histogram.colours(), &setup.palette, k);
+    let list: Vec<String> = indices.iter().map(|i| i.to_string()).collect();
+    let report = if setup.batch {
+        format!("{}: {}\n", image_filename, list.join(","))
+    } else {
+        format!("{}\n", list.join(","))
+    };
+    let rgb = indices.iter().map(|&i| setup.colours[i]).collect();
+    return (Some(Palette::new(rgb, &setup.ill, false)), report);
+}
+
+/// Parses `--threads`, all available cores by default.
+fn threads_from_cmd<'a>(matches: &clap::ArgMatches<'a>) -> Result<usize, String> {
+    match matches.value_of("threads") {
+        Some(x) => {
+            let threads = str::parse(x)
+                .map_err(|e| format!("Could not parse thread count: {}", e))?;
+            if threads == 0 {
+                return Err("Thread count must be at least 1".into());
+            }
+            return Ok(threads);
+        }
+        None => {
+            return Ok(std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1));
+        }
+    }
+}
+
+/// Downscales the image for dithering if requested.
+fn resize_image(data: &Vec<Vec<Option<RGB255>>>, size: Option<(usize, usize)>)
+            -> Vec<Vec<Option<RGB1>>> {
+    match size {
+        Some((w, h)) => { resize_area(data, w, h) }
+        None => {
+            data.iter()
+                .map(|row| row.iter().map(|opt| opt.map(RGB1::from)).collect())
+                .collect()
+        }
+    }
+}
+
+/// Parses sub-palettes given as palette indices, like `0,1,2,3;0,4,5,6`.
+fn subpalettes_from_cmd<'a>(matches: &clap::ArgMatches<'a>, n: usize)
+            -> Result<Option<Vec<Vec<usize>>>, String> {
+    let spec = match matches.value_of("subpalettes") {
+        Some(x) => { x }
+        None => { return Ok(None); }
+    };
+    let mut sets = vec![];
+    for group in spec.split(';') {
+        let mut set = vec![];
+        for index in group.split(',') {
+            let i: usize = str::parse(index.trim())
+                .map_err(|e| format!("Could not parse sub-palette index: {}", e))?;
+            if i >= n {
+                return Err(format!("Sub-palette index {} is out of the palette", i));
+            }
+            set.push(i);
+        }
+        sets.push(set);
+    }
+    return Ok(Some(sets));
+}
+
+/// Writes an ICC profile into a PNG file.
+/// Fails silently.
+fn write_icc_profile(outfile: &str, icc_profile: &img_parts::Bytes) {
+    let data = match std::fs::read(outfile) {
+        Ok(x) => { x }
+        Err(_) => { return; }
+    };
+    let mut png = match Png::from_bytes(data.into()) {
+        Ok(x) => { x }
+        Err(_) => { return; }
+    };
+    png.set_icc_profile(Some(icc_profile.clone()));
+    let file = match std::fs::File::create(outfile) {
+        Ok(x) => { x }
+        Err(_) => { return; }
+    };
+    let _ = png.encoder().write_to(file);
+}
+
+/// Dithers every frame with the same threshold alignment. Pixels that did not change
+/// since the previous frame keep its output, so static areas never flicker.
+/// The result is an animated indexed GIF or APNG with the original frame timings.
+fn dither_animation<'a>(matches: &clap::ArgMatches<'a>, setup: &DitherSetup,
+                        image_filename: &str, outfile: &str, gif_output: bool,
+                        verbose: bool) -> Result<String, String> {
+    let transparent = matches.is_present("transparent");
+    let frame_delay = str::parse(matches.value_of("frame_delay").unwrap_or("100"))
+        .map_err(|e| format!("Could not parse frame delay: {}", e))?;
+    let animation = load_animation(image_filename, setup.alpha, frame_delay)
+        .map_err(|e| format!("Error loading input image: {}", e))?;
+    let n = animation.frames.len();
+    if verbose { eprintln!("Loaded {} frames", n) }
+
+    let scale = setup.scale;
+    let frames: Vec<Vec<Vec<Option<RGB1>>>> = animation.frames.iter()
+        .map(|frame| resize_image(frame, setup.size))
+        .collect();
+
+    let (subset, report) = subset_palette(setup, &frames.iter().collect::<Vec<_>>(),
+                                          image_filename, verbose);
+    let palette = subset.as_ref().unwrap_or(&setup.palette);
+
+    let mut dithered: Vec<PlotData<RGB255>> = vec![];
+    for k in 0..n {
+        if verbose { eprintln!("Dithering frame {}/{}...", k + 1, n) }
+        let frame = &frames[k];
+        let mut result = Ditherer::dither_parallel(frame, &setup.lut, palette,
+                                                   &setup.method, setup.matrix.as_ref(),
+                                                   setup.spread, setup.threads,
+                                                   verbose && k == 0);
+        if k > 0 {
+            let previous_frame = &frames[k - 1];
+            let previous = &dithered[k - 1];
+            for j in 0..frame.len() {
+                for i in 0..frame[j].len() {
+                    if frame[j][i] == previous_frame[j][i] {
+                        result.data[j][i] = previous.data[j][i];
+                    }
+                }
+            }
+        }
+        dithered.push(result);
+    }
+
+    if let Some(usage_file) = named_file(matches, "usage", image_filename) {
+        let data: Vec<_> = dithered.iter().map(|frame| &frame.data).collect();
+        let usage = PaletteUsage::new(&data, &setup.palette, &setup.lut);
+        write_usage(&usage, &setup.palette, &usage_file)?;
+    }
+    if let Some(value_file) = named_file(matches, "value_check", image_filename) {
+        let value_file = output_name(&value_file);
+        let pairs: Vec<_> = frames.iter().zip(dithered.iter()).collect();
+        write_value_check(&pairs, &setup.lut, scale, &value_file, verbose)?;
+    }
+    if scale > 1 {
+        dithered = dithered.iter().map(|frame| scale_nearest(frame, scale)).collect();
+    }
+    let frames = IndexedImage::from_frames(&dithered, &setup.colours, transparent)
+        .map_err(|e| format!("Error saving output image: {}", e))?;
+    let result = if gif_output {
+        save_animated_gif(&frames, &animation.delays, outfile)
+    } else {
+        save_apng(&frames, &animation.delays, outfile)
+    };
+    result.map_err(|e| format!("Error saving output image: {}", e))?;
+
+    if !gif_output {
+        if let Some(ref icc_profile) = animation.icc_profile {
+            write_icc_profile(outfile, icc_profile);
+        }
+    }
+    return Ok(report);
+}
+
+/// A method with typical parameters for `--compare`.
+fn compare_method_from_name<'a>(name: &str, matches: &clap::ArgMatches<'a>)
+            -> Result<DitheringMethod, String> {
+    let serpentine = matches.is_present("serpentine");
+    let strength = str::parse(matches.value_of("strength").unwrap_or("1"))
+        .map_err(|e| format!("Could not parse diffusion strength: {}", e))?;
+    let diffusion = |kernel| DitheringMethod::ErrorDiffusion { kernel, serpentine, strength };
+    let method = match name {
+        "none" => { DitheringMethod::None }
+        "bayer" => { DitheringMethod::Bayer(3) }
+        "whitenoise" => { DitheringMethod::WhiteNoise(14, 14, rand::random()) }
+        "bluenoise" => { DitheringMethod::default() }
+        "dispersed" => { DitheringMethod::Dispersed(5) }
+        "halftone" => { DitheringMethod::Halftone(6) }
+        "lines" => { DitheringMethod::Lines(4, LineDirection::Horizontal) }
+        "crosshatch" => { DitheringMethod::CrossHatch(6) }
+        "ign" => { DitheringMethod::InterleavedGradient(32, 32) }
+        "pattern" => { DitheringMethod::Pattern(3) }
+        "floyd-steinberg" => { diffusion(DiffusionKernel::FloydSteinberg) }
+        "atkinson" => { diffusion(DiffusionKernel::Atkinson) }
+        "jarvis" => { diffusion(DiffusionKernel::JarvisJudiceNinke) }
+        "stucki" => { diffusion(DiffusionKernel::Stucki) }
+        "sierra" => { diffusion(DiffusionKernel::Sierra) }
+        "burkes" => { diffusion(DiffusionKernel::Burkes) }
+        _ => { return Err(format!("Unknown dithering method: {}", name)); }
+    };
+    return Ok(match matches.value_of("seed") {
+        Some(seed) => {
+            let seed = str::parse(seed).map_err(|e| format!("Could not parse seed: {}", e))?;
+            method.with_seed(seed)
+        }
+        None => { method }
+    });
+}
+
+/// Dithers the image with several methods and writes a labelled sheet with the source
+/// followed by the results; returns their quality, one method per line.
+#[allow(clippy::too_many_arguments)]
+fn dither_compare<'a>(matches: &clap::ArgMatches<'a>, image_filename: &str, outfile: &str,
+                      palette: &Palette, ill: &CAT16Illuminant, lut: &CAM16Lut,
+                      alpha: AlphaOptions,
+                      cacher: &mut BigCacher, verbose: bool) -> Result<String, String> {
+    let size = resize_from_cmd(matches)?;
+    let scale = scale_from_cmd(matches)?;
+    let image = load_image(image_filename.into(), alpha)
+        .map_err(|e| format!("Error loading input image: {}", e))?;
+    let resized = resize_image(&image.data, size);
+    if verbose { eprintln!("Converting the image into CAM16UCS...") }
+    let plot = image_to_cam16(&resized, lut);
+
+    let mut names: Vec<&str> = match matches.values_of("compare") {
+        Some(values) => { values.flat_map(|v| v.split(',')).collect() }
+        None => { vec![] }
+    };
+    if names.is_empty() {
+        names = metadata::COMPARE_DEFAULT.split(',').collect();
+    }
+    let spread = spread_from_cmd(matches)?;
+    let mut results = vec![];
+    let mut report = String::new();
+    for name in names.iter() {
+        let method = compare_method_from_name(name.trim(), matches)?;
+        let dithered = Ditherer::dither(plot.clone(), palette, method.clone(), spread,
+                                        cacher, verbose);
+        let quality = DitherQuality::measure(&plot, &dithered, ill);
+        report += &format!("{:<24} mean {:6.2}  p95 {:6.2}\n",
+                           method.name(), quality.mean, quality.p95);
+        results.push((method.name(), Some(quality), dithered));
+    }
+    let source = resized.iter()
+        .map(|row| row.iter().map(|opt| opt.map(RGB255::from)).collect())
+        .collect();
+    results.insert(0, ("source".into(), None, PlotData::new(source)));
+    if scale > 1 {
+        for (_, _, data) in results.iter_mut() {
+            *data = scale_nearest(data, scale);
+        }
+    }
+    let h = results[0].2.data.len() as i32;
+    let w = results[0].2.data[0].len() as i32;
+
+    let font = Font::new();
+    let (pad, line) = (4, 7);
+    let label_w = results.iter()
+        .map(|(name, _, _)| font.str_width(name))
+        .chain(std::iter::once(font.str_width("p95 000.00")))
+        .max().unwrap();
+    let cell_w = i32::max(w, label_w) + pad;
+    let cell_h = h + 2 * line + pad;
+    let cols = (results.len() as f32).sqrt().ceil() as i32;
+    let rows = (results.len() as i32 + cols - 1) / cols;
+    let mut graph = ImageGraph::new((cols * cell_w + pad) as u32, (rows * cell_h + pad) as u32);
+    if let Some(profile) = image.icc_profile {
+        graph = graph.with_icc_profile(profile);
+    }
+    let bg = RGB255::new(0x20, 0x20, 0x20);
+    let fg = RGB255::new(0xe0, 0xe0, 0xe0);
+    graph.block(0, 0, cols * cell_w + pad, rows * cell_h + pad, bg);
+    for (k, (name, quality, data)) in results.iter().enumerate() {
+        let x0 = pad + (k as i32 % cols) * cell_w;
+        let y0 = pad + (k as i32 / cols) * cell_h;
+        for y in 0..h {
+            for x in 0..w {
+                if let Some(rgb) = data.data[y as usize][x as usize] {
+                    graph.put_pixel(x0 + x, y0 + y, rgb);
+                }
+            }
+        }
+        graph.text(name, x0, y0 + h + 2, TextAnchor::nw(), &font, fg);
+        if let Some(q) = quality {
+            let s = format!("dE {:.2} p95 {:.2}", q.mean, q.p95);
+            graph.text(&s, x0, y0 + h + 2 + line, TextAnchor::nw(), &font, fg);
+        }
+    }
+    graph.save(outfile.into()).map_err(|e| format!("Error saving output image: {}", e))?;
+    return Ok(report);
+}
+
+/// Runs the `usage` subcommand.
+pub fn usage<'a>(matches: &clap::ArgMatches<'a>, verbose: bool) -> Result<(), String> {
+    let T = temperature_from_cmd(matches)?;
+    let ill = CAT16Illuminant::new(CIExy::from_T(T));
+
+    let colours = palette_from_cmd(matches, verbose)?.colours;
+    let palette = Palette::new(colours, &ill, false);
+
+    let alpha = alpha_from_cmd(matches)?;
+    let lut = cam16_lut(T, &ill, verbose);
+    let image_filename = matches.value_of("imageinput").unwrap();
+    let images = if is_animation(image_filename) {
+        let animation = load_animation(image_filename, alpha, 100)
+            .map_err(|e| format!("Error loading input image: {}", e))?;
+        animation.frames
+    } else {
+        let image = load_image(image_filename.into(), alpha)
+            .map_err(|e| format!("Error loading input image: {}", e))?;
+        vec![image.data]
+    };
+    if verbose { eprintln!("Mapping the image onto the palette...") }
+    let usage = PaletteUsage::new(&images.iter().collect::<Vec<_>>(), &palette, &lut);
+
+    match matches.value_of("outfile") {
+        Some(outfile) => { write_usage(&usage, &palette, outfile)?; }
+        None => { print!("{}", usage.to_csv(&palette)); }
+    }
+    if let Some(chart) = matches.value_of("chart") {
+        usage_chart(&usage, palette, ill, &output_name(chart))?;
+    }
+    return Ok(());
+}
+
+/// Writes the usage report as JSON for `.json` file names and as CSV otherwise.
+fn write_usage(usage: &PaletteUsage, palette: &Palette, filename: &str) -> Result<(), String> {
+    let data = if filename.ends_with(".json") {
+        usage.to_json(palette).pretty(2)
+    } else {
+        usage.to_csv(palette)
+    };
+    std::fs::write(filename, data).map_err(|e| format!("Error saving usage report: {}", e))
+}
+
+/// Renders the usage histogram with a summary line into `outfile`.
+fn usage_chart(usage: &PaletteUsage, palette: Palette, ill: CAT16Illuminant,
+               outfile: &str) -> Result<(), String> {
+    let font = Font::new();
+    let ww = (256 / palette.n as i32).clip(2, 16);
+    let summary = format!("{} pixels, {} of {} colours unused",
+                          usage.total(), usage.unused().len(), palette.n);
+    let w = i32::max(ww * palette.n as i32 + 4, font.str_width(&summary)) + 8;
+    let h = 96;
+    let mut graph = ImageGraph::new(w as u32, h as u32);
+    graph.block(0, 0, w, h, palette.bg_rgb);
+    graph.block(2, 9, w - 4, h - 11, palette.bl_rgb);
+    graph.text(&summary, 4, 2, TextAnchor::nw(), &font, palette.tl_rgb);
+
+    let graph = Rc::new(RwLock::new(graph));
+    let cache = Rc::new(RwLock::new(NoCacheProvider::new(ill)));
+    let widget = UsageHistogramWidget::new(ww, h - 14, usage.counts.clone());
+    widget.render(graph.clone(), cache, Rc::new(palette), Rc::new(ill), Rc::new(font), 4, 10);
+    let result = graph.read().unwrap().save(outfile.into());
+    result.map_err(|e| format!("Error saving usage chart: {}", e))
+}
+
+/// Runs the `remap` subcommand.
+pub fn remap<'a>(matches: &clap::ArgMatches<'a>, verbose: bool) -> Result<(), String> {
+    let T = temperature_from_cmd(matches)?;
+    let ill = CAT16Illuminant::new(CIExy::from_T(T));
+
+    let to = Palette::new(palette_from_cmd(matches, verbose)?.colours, &ill, false);
+    let alpha = alpha_from_cmd(matches)?;
+    let lut = cam16_lut(T, &ill, verbose);
+
+    let image_filename = matches.value_of("imageinput").unwrap();
+    let image = load_image(image_filename.into(), alpha)
+        .map_err(|e| format!("Error loading input image: {}", e))?;
+    let from = match source_palette_from_cmd(matches, verbose)? {
+        Some(x) => { x }
+        None => { image_colours(image_filename, &image.data) }
+    };
+    if from.len() > 256 {
+        return Err(format!("The source palette has {} colours, at most 256 are supported", from.len()));
+    }
+    let from = Palette::new(from, &ill, false);
+
+    let fixed = match matches.value_of("mapping") {
+        Some(filename) => {
+            let text = std::fs::read_to_string(filename)
+                .map_err(|e| format!("Error reading mapping: {}", e))?;
+            PaletteMapping::parse_overrides(&text, &from, &to)
+                .map_err(|e| format!("Error reading mapping: {}", e))?
+        }
+        None => { HashMap::new() }
+    };
+    if verbose { eprintln!("Assigning {} colours to {} colours...", from.n, to.n) }
+    let mapping = PaletteMapping::optimal(&from, &to, &fixed);
+    print!("{}", mapping.to_text(&from, &to));
+
+    let mut recolourer = Recolourer::new(&mapping, &from, &to, &lut);
+    if let Some(value_file) = matches.value_of("value_check") {
+        let recoloured = image.data.iter()
+            .map(|row| row.iter().map(|px| px.map(|c| recolourer.get(c))).collect())
+            .collect();
+        let source = resize_image(&image.data, None);
+        write_value_check(&[(&source, &PlotData::new(recoloured))], &lut, 1, &output_name(value_file),
+                          verbose)?;
+    }
+    let outfile = matches.value_of("outfile").unwrap();
+    let data = std::fs::read(image_filename)
+        .map_err(|e| format!("Error loading input image: {}", e))?;
+    let recoloured = recolourer.colour_table(&data)
+        .map_err(|e| format!("Error recolouring the colour table: {}", e))?;
+    if let Some(recoloured) = recoloured {
+        let format = if data.starts_with(b"GIF") { "gif" } else if data.starts_with(b"BM") { "bmp" } else { "png" };
+        if !outfile.to_lowercase().ends_with(&format!(".{}", format)) {
+            return Err(format!("Indexed images keep their format, the output name must end with .{}", format));
+        }
+        if verbose { eprintln!("Keeping the indices of the image") }
+        return std::fs::write(outfile, recoloured)
+            .map_err(|e| format!("Error saving output image: {}", e));
+    }
+
+    let outfile = output_name(outfile);
+    let h = image.data.len() as u32;
+    let w = if h > 0 { image.data[0].len() as u32 } else { 0 };
+    let result = if image.data.iter().flatten().any(|px| px.is_none()) {
+        let mut out = RgbaImage::new(w, h);
+        for y in 0..h {
+            for x in 0..w {
+                if let Some(rgb) = image.data[y as usize][x as usize] {
+                    let c = recolourer.get(rgb);
+                    out.put_pixel(x, y, Rgba([c.r, c.g, c.b, 0xff]));
+                }
+            }
+        }
+        out.save(&outfile)
+    } else {
+        let mut out = RgbImage::new(w, h);
+        for y in 0..h {
+            for x in 0..w {
+                if let Some(rgb) = image.data[y as usize][x as usize] {
+                    out.put_pixel(x, y, recolourer.get(rgb).into());
+                }
+            }
+        }
+        out.save(&outfile)
+    };
+    result.map_err(|e| format!("Error saving output image: {}", e))?;
+    if let Some(ref icc_profile) = image.icc_profile {
+        if outfile.ends_with(".png") {
+            write_icc_profile(&outfile, icc_profile);
+        }
+    }
+    return Ok(());
+}
+
+/// The palette the image was made with from the `--from-*` options, if any.
+fn source_palette_from_cmd<'a>(matches: &clap::ArgMatches<'a>, verbose: bool)
+            -> Result<Option<Vec<RGB255>>, String> {
+    let result = if let Some(hex_list) = matches.value_of("from_colours") {
+        let hex_list = hex_list.split(',')
+            .map(|s| String::from(s))
+            .collect::<Vec<_>>();
+        load_from_hex(&hex_list)
+    } else if let Some(filename) = matches.value_of("from_hexfile") {
+        load_from_file(filename.into())
+    } else if let Some(slug) = matches.value_of("from_lospec") {
+        let base_url = matches.value_of("lospec_url").unwrap_or(LOSPEC_URL);
+        let offline = matches.is_present("offline");
+        if verbose { eprintln!("Loading source palette from {}...", base_url); }
+        load_from_lospec(slug.into(), base_url, offline)
+    } else if let Some(filename) = matches.value_of("from_indexed") {
+        load_from_colour_table(filename.into())
+    } else {
+        return Ok(None);
+    };
+    match result {
+        Ok(x) => { Ok(Some(x.colours)) }
+        Err(e) => { Err(format!("Error while getting source palette: {}", e)) }
+    }
+}
+
+/// Colours used by the image, in colour table order for indexed images
+/// and in reading order otherwise.
+fn image_colours(image_filename: &str, data: &Vec<Vec<Option<RGB255>>>) -> Vec<RGB255> {
+    let mut order = load_from_colour_table(image_filename.into())
+        .map(|palette| palette.colours)
+        .unwrap_or_default();
+    order.extend(data.iter().flatten().flatten());
+    let used: HashSet<RGB255> = data.iter().flatten().flatten().copied().collect();
+    let mut seen = HashSet::new();
+    return order.into_iter()
+        .filter(|c| used.contains(c) && seen.insert(*c))
+        .collect();
+}
+
+/// Runs the `coverage` subcommand.
+pub fn coverage<'a>(matches: &clap::ArgMatches<'a>, verbose: bool) -> Result<(), String> {
+    let T = temperature_from_cmd(matches)?;
+    let ill = CAT16Illuminant::new(CIExy::from_T(T));
+
+    let palette = Palette::new(palette_from_cmd(matches, verbose)?.colours, &ill, false);
+    let alpha = alpha_from_cmd(matches)?;
+    let lut = cam16_lut(T, &ill, verbose);
+    let threshold: f32 = str::parse(matches.value_of("threshold").unwrap_or("10"))
+        .map_err(|e| format!("Error parsing threshold: {}", e))?;
+
+    let image_filename = matches.value_of("imageinput").unwrap();
+    let image = load_image(image_filename.into(), alpha)
+        .map_err(|e| format!("Error loading input image: {}", e))?;
+    if verbose { eprintln!("Finding the nearest palette colours...") }
+    let coverage = Coverage::new(&image.data, &palette, &lut);
+
+    let stats = coverage.stats(threshold);
+    println!("mean {:.2}  median {:.2}  p95 {:.2}  max {:.2}",
+             stats.mean, stats.median, stats.p95, stats.max);
+    println!("above {}: {:.1}% of {} pixels", threshold, 100. * stats.failing, stats.pixels);
+    for region in coverage.regions(threshold).iter() {
+        println!("region {},{} {}x{}: {} pixels, mean {:.2}",
+                 region.x, region.y, region.w, region.h, region.pixels, region.mean);
+    }
+    for band in coverage.hue_bands(threshold).iter() {
+        let name = match band.hues {
+            Some((from, to)) => { format!("hue {}-{}", from, to) }
+            None => { "neutral".into() }
+        };
+        println!("{}: {} pixels, mean {:.2}, above {}: {:.1}%",
+                 name, band.pixels, band.mean, threshold, 100. * band.failing);
+    }
+    if let Some(report) = matches.value_of("report") {
+        std::fs::write(report, coverage.to_json(threshold).pretty(2))
+            .map_err(|e| format!("Error saving coverage report: {}", e))?;
+    }
+    if let Some(value_file) = matches.value_of("value_check") {
+        let nearest = image.data.iter()
+            .map(|row| row.iter()
+                .map(|px| px.map(|c| palette.rgb[palette.nearest_index(lut.of(c.into()))]))
+                .collect())
+            .collect();
+        let source = resize_image(&image.data, None);
+        write_value_check(&[(&source, &PlotData::new(nearest))], &lut, 1, &output_name(value_file),
+                          verbose)?;
+    }
+
+    let outfile = output_name(matches.value_of("outfile").unwrap_or("plot.png"));
+    let heatmap = coverage.heatmap(2. * threshold);
+    let h = heatmap.data.len() as u32;
+    let w = if h > 0 { heatmap.data[0].len() as u32 } else { 0 };
+    let mut out = RgbaImage::new(w, h);
+    for y in 0..h {
+        for x in 0..w {
+            if let Some(c) = heatmap.data[y as usize][x as usize] {
+                out.put_pixel(x, y, Rgba([c.r, c.g, c.b, 0xff]));
+            }
+        }
+    }
+    out.save(&outfile).map_err(|e| format!("Error saving output image: {}", e))
+}
+
+/// Runs the `shade` subcommand.
+pub fn shade<'a>(matches: &clap::ArgMatches<'a>, verbose: bool) -> Result<(), String> {
+    let T = temperature_from_cmd(matches)?;
+    let ill = CAT16Illuminant::new(CIExy::from_T(T));
+
+    let palette = Palette::new(palette_from_cmd(matches, verbose)?.colours, &ill, false);
+    let light_hex = matches.value_of("light").unwrap_or("ffffff").trim();
+    let colour = parse_hex(light_hex.trim_start_matches('#').into())
+        .map_err(|e| format!("Error parsing light colour: {}", e))?;
+    let model = LightModel::from_name(matches.value_of("model").unwrap_or("multiply")).unwrap();
+    let light = Light::new(colour, model);
+    let levels = matches.value_of("levels").unwrap_or("0.25,0.5,0.75,1").split(',')
+        .map(|v| str::parse::<f32>(v.trim())
+            .map_err(|e| format!("Error parsing light level {}: {}", v, e)))
+        .collect::<Result<Vec<f32>, String>>()?;
+    if levels.iter().any(|&t| t < 0.) {
+        return Err("Light levels must not be negative".into());
+    }
+
+    let snap = if matches.is_present("snap") { Some(&ill) } else { None };
+    let shaded = ShadedPalette::new(&palette, &light, levels, snap);
+    print!("{}", shaded.to_csv(&palette));
+    let results = shaded.results(&palette);
+
+    if let Some(filename) = matches.value_of("export") {
+        let format = match matches.value_of("format") {
+            Some(name) => { PaletteFormat::from_name(name) }
+            None => { PaletteFormat::from_filename(filename) }
+        };
+        let format = format.ok_or(format!("Unknown output format: {}", filename))?;
+        let name = std::path::Path::new(filename).file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("censor");
+        let colours = LoadedPalette::new(results.iter().flatten().copied().collect());
+        export_palette(&colours, format, name, filename)
+            .map_err(|e| format!("Error while exporting palette: {}", e))?;
+    }
+
+    let outfile = output_name(matches.value_of("outfile").unwrap_or("plot.png"));
+    let font = Font::new();
+    let labels: Vec<String> = shaded.levels.iter().map(|t| format!("{}", t)).collect();
+    let ww = labels.iter().map(|s| font.str_width(s)).max().unwrap_or(0).max(10) + 2;
+    let hh = (480 / palette.n as i32).clip(2, 12);
+    let title = format!("Light {} ({})", colour.hex(), model.name());
+    let grid_w = ww * (results.len() as i32 + 1) + 7;
+    let grid_h = hh * palette.n as i32 + 12;
+    let w = i32::max(grid_w, font.str_width(&title)) + 8;
+    let h = grid_h + 14;
+    let mut graph = ImageGraph::new(w as u32, h as u32);
+    graph.block(0, 0, w, h, palette.bg_rgb);
+    graph.block(2, 9, w - 4, h - 11, palette.bl_rgb);
+    graph.text(&title, 4, 2, TextAnchor::nw(), &font, palette.tl_rgb);
+
+    let graph = Rc::new(RwLock::new(graph));
+    let cache = Rc::new(RwLock::new(NoCacheProvider::new(ill)));
+    let widget = ShadingGridWidget::new(ww, hh, labels, results);
+    widget.render(graph.clone(), cache, Rc::new(palette), Rc::new(ill), Rc::new(font), 4, 10);
+    let result = graph.read().unwrap().save(outfile);
+    result.map_err(|e| format!("Error saving output image: {}", e))
+}
+
+/// Runs the `convert` subcommand.
+pub fn convert<'a>(matches: &clap::ArgMatches<'a>, verbose: bool) -> Result<(), String> {
+    let T = temperature_from_cmd(matches)?;
+    let ill = CAT16Illuminant::new(CIExy::from_T(T));
+
+    let mut outfile: String = matches.value_of("outfile").unwrap().into();
+    let format = match matches.value_of("format") {
+        Some(name) => { PaletteFormat::from_name(name) }
+        None => { PaletteFormat::from_filename(&outfile) }
+    };
+    let format = match format {
+        Some(x) => { x }
+        None => {
+            return Err(format!("Error while exporting palette: {}",
+                               ExportError::UnknownFormat(outfile)));
+        }
+    };
+    let is_image = format == PaletteFormat::Png || format == PaletteFormat::PngScaled;
+    if is_image && !outfile.ends_with(".png") {
+        outfile = format!("{}.png", outfile);
+    }
+
+    let mut palette = palette_from_cmd(matches, verbose)?;
+    if matches.is_present("dedup") {
+        palette.colours = dedup_colours(&palette.colours);
+    }
+    if let Some(key) = matches.value_of("sort") {
+        let order = PaletteOrder::from_name(key).unwrap();
+        palette.colours = sort_colours(&palette.colours, order, &ill);
+    }
+    if matches.is_present("reverse") {
+        palette.colours.reverse();
+    }
+
+    let name = std::path::Path::new(&outfile)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("censor");
+    if verbose { eprintln!("Writing {} colours...", palette.colours.len()); }
+    export_palette(&palette, format, name, &outfile)
+        .map_err(|e| format!("Error while exporting palette: {}", e))
+}
@@ -0,0 +1,298 @@
+use crate::colour::*;
+use crate::palette::Palette;
+use crate::cache::PlotData;
+use crate::dither::*;
+
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HardwareTarget {
+    Nes,
+    ZxSpectrum,
+    C64Multicolour,
+    GameBoyColor
+}
+impl HardwareTarget {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "nes" => { Some(Self::Nes) }
+            "zx" => { Some(Self::ZxSpectrum) }
+            "c64" => { Some(Self::C64Multicolour) }
+            "gbc" => { Some(Self::GameBoyColor) }
+            _ => { None }
+        }
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Nes => { "nes" }
+            Self::ZxSpectrum => { "zx" }
+            Self::C64Multicolour => { "c64" }
+            Self::GameBoyColor => { "gbc" }
+        }
+    }
+    pub fn constraint(self) -> BlockConstraint {
+        match self {
+            // 16x16 attribute blocks, 4 sub-palettes of 3 colours and the backdrop
+            Self::Nes => {
+                BlockConstraint { w: 16, h: 16, colours: 4, subpalettes: Some(4), shared_background: true }
+            }
+            // 8x8 attribute cells with ink and paper
+            Self::ZxSpectrum => {
+                BlockConstraint { w: 8, h: 8, colours: 2, subpalettes: None, shared_background: false }
+            }
+            // 4x8 cells (in double-wide pixels) with 3 colours and the background
+            Self::C64Multicolour => {
+                BlockConstraint { w: 4, h: 8, colours: 4, subpalettes: None, shared_background: true }
+            }
+            // 8x8 tiles, 8 palettes of 4 colours
+            Self::GameBoyColor => {
+                BlockConstraint { w: 8, h: 8, colours: 4, subpalettes: Some(8), shared_background: false }
+            }
+        }
+    }
+}
+
+/// Colour limits of a block based video mode.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BlockConstraint {
+    pub w: usize,
+    pub h: usize,
+    /// Colours per block, including the shared background.
+    pub colours: usize,
+    /// Number of sub-palettes to choose from, or any colours per block if `None`.
+    pub subpalettes: Option<usize>,
+    /// Whether one colour is the same in every block.
+    pub shared_background: bool
+}
+
+pub struct ConstrainedResult {
+    pub image: PlotData<RGB255>,
+    /// Sub-palettes as indices into the palette, sorted.
+    pub subpalettes: Vec<Vec<usize>>,
+    /// Sub-palette of every block, by block row.
+    pub blocks: Vec<Vec<usize>>
+}
+impl ConstrainedResult {
+    pub fn to_json(&self, target: HardwareTarget) -> json::JsonValue {
+        let constraint = target.constraint();
+        json::object! {
+            "target": target.name(),
+            "block_width": constraint.w,
+            "block_height": constraint.h,
+            "subpalettes": self.subpalettes.clone(),
+            "blocks": self.blocks.clone()
+        }
+    }
+}
+
+/// Colour reduction where every block may only use a few palette colours.
+///
+/// Blocks get the colour set minimising the nearest colour error of their pixels.
+/// With a limited number of sub-palettes, these are found by clustering
+/// the blocks (unless given), then every block picks the best one.
+/// Finally every block is dithered within its colours, keeping thresholds
+/// aligned with the whole image.
+pub struct ConstrainedDither {}
+impl ConstrainedDither {
+    #[allow(clippy::too_many_arguments)]
+    pub fn dither(input: PlotData<CAM16UCS>, palette: &Palette, ill: &CAT16Illuminant,
+                  constraint: BlockConstraint, fixed: Option<Vec<Vec<usize>>>,
+                  method: &DitheringMethod, spread: Spread, matrix: Option<&ThresholdMatrix>,
+                  verbose: bool)
+                    -> ConstrainedResult {
+        let h = input.data.len();
+        let w = if h > 0 { input.data[0].len() } else { 0 };
+        let bw = w.div_ceil(constraint.w);
+        let bh = h.div_ceil(constraint.h);
+        let k = usize::min(constraint.colours, palette.n);
+
+        if verbose { eprintln!("Measuring blocks ({}x{})...", bw, bh) }
+        // Distances from every opaque pixel of a block to every palette colour
+        let mut distances: Vec<Vec<Vec<f32>>> = vec![];
+        for by in 0..bh {
+            for bx in 0..bw {
+                let mut block = vec![];
+                for y in by * constraint.h..usize::min((by + 1) * constraint.h, h) {
+                    for x in bx * constraint.w..usize::min((bx + 1) * constraint.w, w) {
+                        if let Some(c) = input.data[y][x] {
+                            block.push(
+                                palette.cam16.iter().map(|p| CAM16UCS::dist(&c, p)).collect()
+                            );
+                        }
+                    }
+                }
+                distances.push(block);
+            }
+        }
+
+        let forced = if constraint.shared_background {
+            vec![Self::background(&distances, palette.n)]
+        } else {
+            vec![]
+        };
+        let (subpalettes, assignment) = match (fixed, constraint.subpalettes) {
+            (Some(sets), _) => {
+                let assignment = distances.iter()
+                    .map(|block| Self::best_of(block, &sets))
+                    .collect();
+                (sets, assignment)
+            }
+            (None, Some(m)) => {
+                if verbose { eprintln!("Choosing {} sub-palettes...", m) }
+                Self::cluster(&distances, palette.n, k, &forced, m)
+            }
+            (None, None) => {
+                let mut sets: Vec<Vec<usize>> = vec![];
+                let mut assignment = vec![];
+                for block in distances.iter() {
+                    let set = Self::best_set(block.iter(), palette.n, k, &forced);
+                    let i = match sets.iter().position(|s| *s == set) {
+                        Some(i) => { i }
+                        None => {
+                            sets.push(set);
+                            sets.len() - 1
+                        }
+                    };
+                    assignment.push(i);
+                }
+                (sets, assignment)
+            }
+        };
+
+        if verbose { Ditherer::announce(method) }
+        let palettes: Vec<Palette> = subpalettes.iter()
+            .map(|set| {
+                let rgb = set.iter().map(|&i| palette.rgb[i]).collect();
+                Palette::new(rgb, ill, false)
+            })
+            .collect();
+        let mut output = vec![vec![None; w]; h];
+        for by in 0..bh {
+            for bx in 0..bw {
+                let (x0, y0) = (bx * constraint.w, by * constraint.h);
+                let (x1, y1) = (usize::min(x0 + constraint.w, w), usize::min(y0 + constraint.h, h));
+                let block: Vec<Vec<Option<CAM16UCS>>> = input.data[y0..y1].iter()
+                    .map(|row| row[x0..x1].to_vec())
+                    .collect();
+                let sub = &palettes[assignment[by * bw + bx]];
+                let result = Ditherer::dither_region(
+                    PlotData::new(block), sub, method, spread, matrix, x0, y0
+                );
+                for y in y0..y1 {
+                    for x in x0..x1 {
+                        output[y][x] = result.data[y - y0][x - x0];
+                    }
+                }
+            }
+        }
+
+        let blocks = assignment.chunks(bw).map(|row| row.to_vec()).collect();
+        return ConstrainedResult { image: PlotData::new(output), subpalettes, blocks };
+    }
+    /// The colour that is nearest for most pixels.
+    fn background(distances: &Vec<Vec<Vec<f32>>>, n: usize) -> usize {
+        let mut counts = vec![0; n];
+        for pixel in distances.iter().flatten() {
+            counts[Self::argmin(pixel)] += 1;
+        }
+        return Self::argmax(&counts);
+    }
+    fn argmin(values: &Vec<f32>) -> usize {
+        let mut best = 0;
+        for i in 1..values.len() {
+            if values[i] < values[best] { best = i; }
+        }
+        return best;
+    }
+    fn argmax(values: &Vec<usize>) -> usize {
+        let mut best = 0;
+        for i in 1..values.len() {
+            if values[i] > values[best] { best = i; }
+        }
+        return best;
+    }
+    fn cost<'a, I: Iterator<Item = &'a Vec<f32>>>(pixels: I, set: &Vec<usize>) -> f32 {
+        pixels
+            .map(|d| set.iter().map(|&i| d[i]).fold(f32::MAX, f32::min))
+            .sum()
+    }
+    fn best_of(block: &Vec<Vec<f32>>, sets: &Vec<Vec<usize>>) -> usize {
+        let costs: Vec<f32> = sets.iter().map(|set| Self::cost(block.iter(), set)).collect();
+        return Self::argmin(&costs);
+    }
+    /// Greedily picks `k` colours including the forced ones,
+    /// then swaps colours while that helps.
+    fn best_set<'a, I: Iterator<Item = &'a Vec<f32>> + Clone>
+            (pixels: I, n: usize, k: usize, forced: &Vec<usize>) -> Vec<usize> {
+        let mut set = forced.clone();
+        while set.len() < k {
+            let candidates: Vec<usize> = (0..n).filter(|c| !set.contains(c)).collect();
+            let costs: Vec<f32> = candidates.iter()
+                .map(|&c| {
+                    let mut s = set.clone();
+                    s.push(c);
+                    Self::cost(pixels.clone(), &s)
+                })
+                .collect();
+            set.push(candidates[Self::argmin(&costs)]);
+        }
+        let mut current = Self::cost(pixels.clone(), &set);
+        let mut improved = true;
+        let mut rounds = 0;
+        while improved && rounds < 4 {
+            improved = false;
+            rounds += 1;
+            for slot in forced.len()..set.len() {
+                for c in 0..n {
+                    if set.contains(&c) { continue; }
+                    let mut s = set.clone();
+                    s[slot] = c;
+                    let cost = Self::cost(pixels.clone(), &s);
+                    if cost < current {
+                        set = s;
+                        current = cost;
+                        improved = true;
+                    }
+                }
+            }
+        }
+        set.sort();
+        return set;
+    }
+    /// Finds `m` sub-palettes by alternating block assignment and refitting,
+    /// starting from the most common per-block colour sets.
+    fn cluster(distances: &Vec<Vec<Vec<f32>>>, n: usize, k: usize, forced: &Vec<usize>,
+               m: usize) -> (Vec<Vec<usize>>, Vec<usize>) {
+        let own: Vec<Vec<usize>> = distances.iter()
+            .map(|block| Self::best_set(block.iter(), n, k, forced))
+            .collect();
+        let mut counts: HashMap<Vec<usize>, usize> = HashMap::new();
+        for set in own.iter() {
+            *counts.entry(set.clone()).or_insert(0) += 1;
+        }
+        let mut common: Vec<(Vec<usize>, usize)> = counts.into_iter().collect();
+        common.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        let mut sets: Vec<Vec<usize>> = common.into_iter().take(m).map(|(s, _)| s).collect();
+        let mut assignment: Vec<usize> = vec![usize::MAX; distances.len()];
+        for _ in 0..10 {
+            let next: Vec<usize> = distances.iter()
+                .map(|block| Self::best_of(block, &sets))
+                .collect();
+            if next == assignment { break; }
+            assignment = next;
+            for i in 0..sets.len() {
+                let pixels = distances.iter()
+                    .zip(assignment.iter())
+                    .filter(|&(_, &a)| a == i)
+                    .flat_map(|(block, _)| block.iter());
+                if pixels.clone().next().is_some() {
+                    sets[i] = Self::best_set(pixels, n, k, forced);
+                }
+            }
+        }
+        let assignment = distances.iter()
+            .map(|block| Self::best_of(block, &sets))
+            .collect();
+        return (sets, assignment);
+    }
+}
@@ -0,0 +1,223 @@
+use crate::colour::*;
+use crate::palette::Palette;
+use crate::cache::PlotData;
+use crate::util::Clip;
+
+/// Nearest palette colour error of every pixel of an image.
+pub struct Coverage {
+    w: usize,
+    h: usize,
+    /// Image colour and its CAM16UCS distance to the nearest palette colour.
+    pixels: Vec<Vec<Option<(CAM16UCS, f32)>>>
+}
+
+/// Summary of the errors of all opaque pixels.
+pub struct CoverageStats {
+    pub pixels: usize,
+    pub mean: f32,
+    pub median: f32,
+    pub p95: f32,
+    pub max: f32,
+    /// Share of pixels with an error above the threshold.
+    pub failing: f32
+}
+
+/// Connected area of cells with a mean error above the threshold.
+pub struct CoverageRegion {
+    pub x: usize,
+    pub y: usize,
+    pub w: usize,
+    pub h: usize,
+    pub pixels: usize,
+    pub mean: f32
+}
+
+/// Pixels within a range of CAM16UCS hue angles, or the neutral ones.
+pub struct HueBand {
+    /// Hue range in degrees, `None` for neutral pixels.
+    pub hues: Option<(f32, f32)>,
+    pub pixels: usize,
+    pub mean: f32,
+    pub failing: f32
+}
+
+impl Coverage {
+    /// Side of the square cells that failing regions are made of.
+    pub const CELL: usize = 16;
+    pub const HUE_BANDS: usize = 12;
+    /// Pixels with a smaller a'b' radius don't belong to any hue band.
+    pub const NEUTRAL: f32 = 5.;
+
+    pub fn new(image: &Vec<Vec<Option<RGB255>>>, palette: &Palette, lut: &CAM16Lut) -> Self {
+        let h = image.len();
+        let w = if h > 0 { image[0].len() } else { 0 };
+        let pixels = image.iter()
+            .map(|row| row.iter()
+                .map(|px| px.map(|rgb| {
+                    let c = lut.of(rgb.into());
+                    let nearest = palette.cam16[palette.nearest_index(c)];
+                    (c, CAM16UCS::dist(&c, &nearest))
+                }))
+                .collect())
+            .collect();
+        Self { w, h, pixels }
+    }
+
+    pub fn stats(&self, threshold: f32) -> CoverageStats {
+        let mut errors: Vec<f32> = self.pixels.iter().flatten().flatten().map(|&(_, e)| e).collect();
+        let n = errors.len();
+        if n == 0 {
+            return CoverageStats { pixels: 0, mean: 0., median: 0., p95: 0., max: 0., failing: 0. };
+        }
+        errors.sort_by(|a, b| a.total_cmp(b));
+        let mean = errors.iter().sum::<f32>() / n as f32;
+        let failing = errors.iter().filter(|&&e| e > threshold).count() as f32 / n as f32;
+        let percentile = |p: f32| errors[((n - 1) as f32 * p).round() as usize];
+        CoverageStats {
+            pixels: n, mean, median: percentile(0.5), p95: percentile(0.95), max: errors[n - 1],
+            failing
+        }
+    }
+
+    /// Failing regions, largest total error first.
+    pub fn regions(&self, threshold: f32) -> Vec<CoverageRegion> {
+        let cw = self.w.div_ceil(Self::CELL);
+        let ch = self.h.div_ceil(Self::CELL);
+        let mut cells = vec![vec![(0., 0); cw]; ch];
+        for (y, row) in self.pixels.iter().enumerate() {
+            for (x, px) in row.iter().enumerate() {
+                if let Some((_, e)) = px {
+                    let cell = &mut cells[y / Self::CELL][x / Self::CELL];
+                    cell.0 += e;
+                    cell.1 += 1;
+                }
+            }
+        }
+        let failing = |(sum, n): (f32, usize)| n > 0 && sum / n as f32 > threshold;
+
+        let mut seen = vec![vec![false; cw]; ch];
+        let mut regions = vec![];
+        for j in 0..ch {
+            for i in 0..cw {
+                if seen[j][i] || !failing(cells[j][i]) { continue; }
+                seen[j][i] = true;
+                let mut stack = vec![(i, j)];
+                let (mut x0, mut y0, mut x1, mut y1) = (i, j, i, j);
+                let (mut sum, mut pixels) = (0., 0);
+                while let Some((ci, cj)) = stack.pop() {
+                    x0 = usize::min(x0, ci);
+                    y0 = usize::min(y0, cj);
+                    x1 = usize::max(x1, ci);
+                    y1 = usize::max(y1, cj);
+                    sum += cells[cj][ci].0;
+                    pixels += cells[cj][ci].1;
+                    let neighbours = [
+                        (ci.wrapping_sub(1), cj), (ci + 1, cj), (ci, cj.wrapping_sub(1)), (ci, cj + 1)
+                    ];
+                    for &(ni, nj) in neighbours.iter() {
+                        if ni >= cw || nj >= ch || seen[nj][ni] || !failing(cells[nj][ni]) {
+                            continue;
+                        }
+                        seen[nj][ni] = true;
+                        stack.push((ni, nj));
+                    }
+                }
+                let x = x0 * Self::CELL;
+                let y = y0 * Self::CELL;
+                regions.push(CoverageRegion {
+                    x, y,
+                    w: usize::min((x1 + 1) * Self::CELL, self.w) - x,
+                    h: usize::min((y1 + 1) * Self::CELL, self.h) - y,
+                    pixels,
+                    mean: sum / pixels as f32
+                });
+            }
+        }
+        regions.sort_by(|a, b| (b.mean * b.pixels as f32).total_cmp(&(a.mean * a.pixels as f32)));
+        return regions;
+    }
+
+    /// Bands of equal hue ranges followed by the neutral pixels; empty bands are skipped.
+    pub fn hue_bands(&self, threshold: f32) -> Vec<HueBand> {
+        let k = Self::HUE_BANDS;
+        let mut bands = vec![(0., 0, 0); k + 1];
+        for &(c, e) in self.pixels.iter().flatten().flatten() {
+            let band = if f32::hypot(c.a, c.b) < Self::NEUTRAL {
+                k
+            } else {
+                let hue = f32::atan2(c.b, c.a).to_degrees().rem_euclid(360.);
+                usize::min((hue / 360. * k as f32) as usize, k - 1)
+            };
+            bands[band].0 += e;
+            bands[band].1 += 1;
+            if e > threshold { bands[band].2 += 1; }
+        }
+        let step = 360. / k as f32;
+        return bands.iter().enumerate()
+            .filter(|(_, &(_, n, _))| n > 0)
+            .map(|(i, &(sum, n, failing))| HueBand {
+                hues: if i < k { Some((i as f32 * step, (i + 1) as f32 * step)) } else { None },
+                pixels: n,
+                mean: sum / n as f32,
+                failing: failing as f32 / n as f32
+            })
+            .collect();
+    }
+
+    /// Errors coloured from black (none) through purple, red and orange to pale
+    /// yellow at `max` and above.
+    pub fn heatmap(&self, max: f32) -> PlotData<RGB255> {
+        const STOPS: [(u8, u8, u8); 5] = [
+            (0x00, 0x00, 0x00), (0x50, 0x10, 0x90), (0xd0, 0x20, 0x40),
+            (0xff, 0x90, 0x00), (0xff, 0xff, 0xa0)
+        ];
+        let colour = |e: f32| {
+            let t = (e / max).clip(0., 1.) * (STOPS.len() - 1) as f32;
+            let k = usize::min(t as usize, STOPS.len() - 2);
+            let f = t - k as f32;
+            let (a, b) = (STOPS[k], STOPS[k + 1]);
+            let mix = |x: u8, y: u8| (x as f32 + (y as f32 - x as f32) * f).round() as u8;
+            RGB255::new(mix(a.0, b.0), mix(a.1, b.1), mix(a.2, b.2))
+        };
+        let data = self.pixels.iter()
+            .map(|row| row.iter().map(|px| px.map(|(_, e)| colour(e))).collect())
+            .collect();
+        return PlotData::new(data);
+    }
+
+    pub fn to_json(&self, threshold: f32) -> json::JsonValue {
+        let stats = self.stats(threshold);
+        let regions: Vec<json::JsonValue> = self.regions(threshold).iter()
+            .map(|r| json::object! {
+                "x": r.x, "y": r.y, "w": r.w, "h": r.h,
+                "pixels": r.pixels,
+                "mean": r.mean
+            })
+            .collect();
+        let bands: Vec<json::JsonValue> = self.hue_bands(threshold).iter()
+            .map(|b| {
+                let hues = match b.hues {
+                    Some((from, to)) => { json::array![from, to] }
+                    None => { json::JsonValue::Null }
+                };
+                json::object! {
+                    "hues": hues,
+                    "pixels": b.pixels,
+                    "mean": b.mean,
+                    "failing": b.failing
+                }
+            })
+            .collect();
+        json::object! {
+            "threshold": threshold,
+            "pixels": stats.pixels,
+            "mean": stats.mean,
+            "median": stats.median,
+            "p95": stats.p95,
+            "max": stats.max,
+            "failing": stats.failing,
+            "regions": regions,
+            "hue_bands": bands
+        }
+    }
+}
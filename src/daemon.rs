@@ -1,16 +1,11 @@
-use escape_string;
-use image::RgbImage;
-use img_parts::{png::Png, ImageICC};
-use text_io::scan;
-
 use crate::text::Font;
 use crate::cache::*;
 use crate::analyse::*;
 use crate::loader::*;
 use crate::colour::*;
 use crate::palette::*;
-use crate::dither::*;
 use crate::metadata;
+use crate::commands::{self, palette_from_cmd};
 
 use std::io::{BufRead, Write};
 use std::net::{TcpListener, TcpStream};
@@ -19,7 +14,7 @@ use std::sync::{Arc, RwLock};
 use std::rc::Rc;
 
 pub fn run(port: u16, verbose: bool) -> std::io::Result<()> {
-    let listener = TcpListener::bind(&format!("127.0.0.1:{}", port))?;
+    let listener = TcpListener::bind(format!("127.0.0.1:{}", port))?;
     let addr = listener.local_addr()?;
     eprintln!("Started daemon on port {}", addr.port());
 
@@ -94,56 +89,13 @@ fn process<'a, 'b>(mut stream: TcpStream, parser: clap::App<'a, 'b>,
         return;
     }
     if let Some(matches) = matches.subcommand_matches("dither") {
-        daemon_dither(&mut stream, matches);
+        daemon_dither(&mut stream, matches, cacher);
         return;
     }
 
     return abort(&mut stream, "Invalid command".into());
 }
 
-fn palette_from_cmd<'a>(matches: &clap::ArgMatches<'a>, verbose: bool)
-            -> Result<LoadedPalette, String> {
-    let list_provided = matches.value_of("colours").is_some();
-    let file_provided = matches.value_of("hexfile").is_some();
-    let slug_provided = matches.value_of("lospec").is_some();
-    let image_provided = matches.value_of("imagefile").is_some();
-
-    let result;
-
-    match (list_provided, file_provided, slug_provided, image_provided) {
-        (true, false, false, false) => {
-            let hex_list = matches.value_of("colours").unwrap();
-            let hex_list = hex_list.split(',')
-                .map(|s| String::from(s))
-                .collect::<Vec<_>>();
-            result = load_from_hex(&hex_list);
-        }
-        (false, true, false, false) => {
-            let filename = matches.value_of("hexfile").unwrap();
-            result = load_from_file(filename.into());
-        }
-        (false, false, true, false) => {
-            let slug = matches.value_of("lospec").unwrap();
-            if verbose { eprintln!("Downloading palette..."); }
-            result = load_from_lospec(slug.into());
-        }
-        (false, false, false, true) => {
-            let filename = matches.value_of("imagefile").unwrap();
-            result = load_from_image(filename.into());
-        }
-        _ => {
-            return Err("Impossible happened! Blame the `clap` library. Report this error.".into());
-        }
-    }
-    let palette = match result {
-        Ok(x) => { x }
-        Err(e) => {
-            return Err(format!("Error while getting palette: {}", e));
-        }
-    };
-    return Ok(palette);
-}
-
 fn daemon_analyse<'a>(stream: &mut TcpStream, matches: &clap::ArgMatches<'a>,
             font: Arc<Font>, cacher: &mut BigCacher, verbose: bool) {
     let grey_ui = matches.is_present("grey_ui");
@@ -240,15 +192,14 @@ fn daemon_compute<'a>(stream: &mut TcpStream, matches: &clap::ArgMatches<'a>) {
 
     for metric in metrics {
         if enabled[metric] {
-            let v: String;
-            match metric {
+            let v = match metric {
                 "iss" => {
                     let iss = palette.internal_similarity();
-                    v = format!("{:.2}", iss);
+                    format!("{:.2}", iss)
                 }
                 "acyclic" => {
                     let acyclic = palette.is_acyclic();
-                    v = format!("{}", acyclic);
+                    format!("{}", acyclic)
                 }
                 _ => { continue; }
             };
@@ -257,133 +208,11 @@ fn daemon_compute<'a>(stream: &mut TcpStream, matches: &clap::ArgMatches<'a>) {
     }
 }
 
-fn daemon_dither<'a>(stream: &mut TcpStream, matches: &clap::ArgMatches<'a>) {
-    let T: f32;
-    if let Some(D) = matches.value_of("D") {
-        match D {
-            "50" => { T = 5000.00 }
-            "55" => { T = 5500.00 }
-            "65" => { T = 6503.51 }
-            _ => {
-                return abort(stream, format!("Invalid illuminant preset: D{}", D));
-            }
-        }
-    } else {
-        T = match str::parse(matches.value_of("T").unwrap_or("5500")) {
-            Ok(x) => { x }
-            Err(e) => {
-                return abort(stream, format!("Error parsing temperature: {}", e));
-            }
-        };
-    }
-    let ill = CAT16Illuminant::new(CIExy::from_T(T));
-
-    let mut outfile: String = matches.value_of("outfile").unwrap().into();
-    if !outfile.ends_with(".png") {
-        outfile = format!("{}.png", outfile);
-    }
-
-    let palette = match palette_from_cmd(matches, false) {
-        Ok(x) => { x }
+fn daemon_dither<'a>(stream: &mut TcpStream, matches: &clap::ArgMatches<'a>,
+            cacher: &mut BigCacher) {
+    match commands::dither(matches, cacher, false) {
+        Ok(report) => { let _ = stream.write(report.as_bytes()); }
         Err(e) => { return abort(stream, e); }
-    };
-    let palette = Palette::new(palette.colours.clone(), &ill, false);
-
-    let image_filename = matches.value_of("imageinput").unwrap();
-    let image = match load_image(image_filename.into()) {
-        Ok(x) => { x }
-        Err(e) => {
-            return abort(stream, format!("Error loading input image: {}", e));
-        }
-    };
-    let h = image.data.len() as u32;
-    let w = image.data[0].len() as u32;
-
-    let icc_profile = image.icc_profile;
-    let image_cam16: Vec<Vec<Option<CAM16UCS>>> = image.data.iter().map(
-        |row| row.iter().map(
-            |opt| opt.map(
-                |rgb| CAM16UCS::of(CIEXYZ::from(rgb), &ill)
-            )
-        ).collect()
-    ).collect();
-    let plot = PlotData::new(image_cam16);
-
-    let nodither_provided = matches.is_present("nodither");
-    let bayer_provided = matches.is_present("bayer");
-    let whitenoise_provided = matches.is_present("whitenoise");
-    let bluenoise_provided = matches.is_present("bluenoise");
-
-    let method = match () {
-        () if nodither_provided => { DitheringMethod::None }
-        () if bayer_provided => {
-            let n = match str::parse(matches.value_of("bayer").unwrap()) {
-                Ok(x) => { x }
-                Err(e) => {
-                    return abort(stream, format!("Could not parse Bayer matrix size: {}", e));
-                }
-            };
-            DitheringMethod::Bayer(n)
-        }
-        () if whitenoise_provided => {
-            let wxh = matches.value_of("whitenoise").unwrap();
-            let w: usize;
-            let h: usize;
-            scan!(wxh.bytes() => "{}x{}", w, h);
-            DitheringMethod::WhiteNoise(w, h)
-        }
-        () if bluenoise_provided => {
-            let wxh = matches.value_of("bluenoise").unwrap();
-            let w: usize;
-            let h: usize;
-            scan!(wxh.bytes() => "{}x{}", w, h);
-            DitheringMethod::BlueNoise(w, h)
-        }
-        () => { DitheringMethod::default() }
-    };
-
-    let dithered = Ditherer::dither(plot, &palette, method, false);
-
-    let mut image = RgbImage::new(w, h);
-    for y in 0..h {
-        for x in 0..w {
-            match dithered.data[y as usize][x as usize] {
-                Some(rgb) => {
-                    image.put_pixel(x, y, rgb.into());
-                }
-                None => {}
-            }
-        }
-    }
-    if let Err(e) = image.save(&outfile) {
-        return abort(stream, format!("Error saving output image: {}", e));
     }
-
-    if let Some(ref icc_profile) = icc_profile {
-        let data = match std::fs::read(&outfile) {
-            Ok(x) => { x }
-            Err(_) => {
-                let _ = stream.write("OK\n".as_bytes());
-                return;
-            }
-        };
-        let mut png = match Png::from_bytes(data.into()) {
-            Ok(x) => { x }
-            Err(_) => {
-                let _ = stream.write("OK\n".as_bytes());
-                return;
-            }
-        };
-        png.set_icc_profile(Some(icc_profile.clone()));
-        let file = match std::fs::File::create(&outfile) {
-            Ok(x) => { x }
-            Err(_) => {
-                let _ = stream.write("OK\n".as_bytes());
-                return;
-            }
-        };
-        let _ = png.encoder().write_to(file);
-    }
-
     let _ = stream.write("OK\n".as_bytes());
 }
@@ -1,4 +1,6 @@
 use rand::seq::SliceRandom;
+use rand::{SeedableRng, rngs::StdRng};
+use serde::{Serialize, Deserialize};
 
 use crate::colour::*;
 use crate::palette::*;
@@ -10,6 +12,7 @@ pub trait ThresholdStructure {
     fn at(&self, x: usize, y: usize) -> f32;
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ThresholdMatrix {
     w: usize,
     h: usize,
@@ -20,6 +23,27 @@ impl ThresholdMatrix {
     pub fn new(w: usize, h: usize, order: Vec<Vec<usize>>) -> Self {
         Self { w, h, order }
     }
+    /// Ranks the cells by score, lower scores first; ties keep the scanning order.
+    pub fn from_scores(w: usize, h: usize, scores: &Vec<Vec<f32>>) -> Self {
+        if w == 0 || h == 0 {
+            return Self { w: 0, h: 0, order: vec![] };
+        }
+        let mut cells: Vec<(usize, usize)> = vec![];
+        for j in 0..h {
+            for i in 0..w {
+                cells.push((i, j));
+            }
+        }
+        cells.sort_by_key(|&(i, j)| PackedF32(scores[j][i]));
+        let mut order = vec![vec![0; w]; h];
+        for (rank, &(i, j)) in cells.iter().enumerate() {
+            order[j][i] = rank;
+        }
+        Self { w, h, order }
+    }
+    pub fn size(&self) -> (usize, usize) {
+        (self.w, self.h)
+    }
     pub fn binary(&self) -> Vec<Vec<bool>> {
         let mut data = vec![vec![false; self.w]; self.h];
         for i in 0..self.w {
@@ -51,17 +75,18 @@ impl ThresholdMatrix {
             order
         }
     }
-    pub fn whitenoise(w: usize, h: usize) -> Self {
-        fn random_permutation(n: usize) -> Vec<usize> {
+    /// Random permutation, deterministic for a given seed.
+    pub fn whitenoise(w: usize, h: usize, seed: u64) -> Self {
+        fn random_permutation(n: usize, seed: u64) -> Vec<usize> {
             let mut data = vec![0; n];
             for i in 0..n {
                 data[i] = i;
             }
-            let mut rng = rand::thread_rng();
+            let mut rng = StdRng::seed_from_u64(seed);
             data.shuffle(&mut rng);
             return data;
         }
-        let perm = random_permutation(w * h);
+        let perm = random_permutation(w * h, seed);
         let mut order = vec![vec![0; w]; h];
         for i in 0..w {
             for j in 0..h {
@@ -70,89 +95,187 @@ impl ThresholdMatrix {
         }
         Self { w, h, order }
     }
-    fn cluster<T: PartialEq>(data: &Vec<Vec<T>>, w: usize, h: usize, val: T) -> Vec<Vec<f32>> {
-        let s = 1.5;
-        let mut cluster = vec![vec![0.; w]; h];
-        let radius = i32::min(w as i32, h as i32) / 2;
-        for x in 0..w as i32 {
-            for y in 0..h as i32 {
-                if data[y as usize][x as usize] == val {
-                    for xi in x-radius..=x+radius {
-                        let xx = xi.cyclic_clip(w as i32);
-                        for yi in y-radius..=y+radius {
-                            let yy = yi.cyclic_clip(h as i32);
-                            if data[yy as usize][xx as usize] == val {
-                                let xmin = i32::min(x, xx);
-                                let xmax = i32::max(x, xx);
-                                let ymin = i32::min(y, yy);
-                                let ymax = i32::max(y, yy);
-                                let dx = i32::min(xmax - xmin, w as i32 + xmin - xmax) as f32;
-                                let dy = i32::min(ymax - ymin, h as i32 + ymin - ymax) as f32;
-                                let dr = f32::hypot(dx, dy);
-                                let t = dr / s;
-                                cluster[y as usize][x as usize] += f32::exp(-t.powi(2) / 2.);
-                            }
-                        }
-                    }
-                }
+    /// Bayer-like dispersed dot matrix of any size (e.g. 3x3 or 5x5):
+    /// every next cell is the largest void left.
+    pub fn dispersed(n: usize) -> Self {
+        let mut field = EnergyField::new(n, n);
+        let mut pattern = vec![false; n * n];
+        let mut order = vec![0; n * n];
+        for rank in 0..n * n {
+            let v = field.extreme(&pattern, false);
+            pattern[v] = true;
+            field.toggle(v, 1.);
+            order[v] = rank;
+        }
+        let order = order.chunks(n).map(|row| row.to_vec()).collect();
+        Self { w: n, h: n, order }
+    }
+    /// Clustered dot halftone screen with round dots growing from the cell centre.
+    pub fn halftone(n: usize) -> Self {
+        use std::f32::consts::PI;
+        let c = (n as f32 - 1.) / 2.;
+        let mut scores = vec![vec![0.; n]; n];
+        for j in 0..n {
+            for i in 0..n {
+                let (dx, dy) = (i as f32 - c, j as f32 - c);
+                // the angle only breaks ties, spiralling the dot out
+                let angle = f32::atan2(dy, dx).rem_euclid(2. * PI) / (2. * PI);
+                scores[j][i] = dx.powi(2) + dy.powi(2) + 1e-3 * angle;
             }
         }
-        return cluster;
+        Self::from_scores(n, n, &scores)
     }
-    fn argmax(data: &Vec<Vec<f32>>, w: usize, h: usize) -> (usize, usize) {
-        let (mut x, mut y) = (0, 0);
-        let mut v_max = data[0][0];
-        for i in 0..w {
-            for j in 0..h {
-                let v = data[j][i];
-                if v > v_max {
-                    x = i;
-                    y = j;
-                    v_max = v;
-                }
+    /// Distance from the centre of a period of `n`, ties dispersed by a Bayer matrix.
+    fn line_scores(n: usize, f: impl Fn(usize, usize) -> f32) -> Vec<Vec<f32>> {
+        let c = (n as f32 - 1.) / 2.;
+        let k = (n as f32).log2().ceil() as u32;
+        let bayer = Self::bayer(k);
+        let mut scores = vec![vec![0.; n]; n];
+        for j in 0..n {
+            for i in 0..n {
+                scores[j][i] = (f(i, j) - c).abs() + 1e-3 * bayer.at(i, j);
             }
         }
-        return (x, y);
+        return scores;
+    }
+    /// Line screen with a period of `n` pixels.
+    pub fn lines(n: usize, direction: LineDirection) -> Self {
+        let scores = match direction {
+            LineDirection::Horizontal => { Self::line_scores(n, |_, j| j as f32) }
+            LineDirection::Vertical => { Self::line_scores(n, |i, _| i as f32) }
+            LineDirection::Diagonal => { Self::line_scores(n, |i, j| ((i + j) % n) as f32) }
+        };
+        Self::from_scores(n, n, &scores)
     }
-    pub fn bluenoise(w: usize, h: usize) -> Self {
+    /// Two crossing diagonal line screens with a period of `n` pixels.
+    pub fn crosshatch(n: usize) -> Self {
+        let d1 = Self::line_scores(n, |i, j| ((i + j) % n) as f32);
+        let d2 = Self::line_scores(n, |i, j| ((i + n - j) % n) as f32);
+        let mut scores = vec![vec![0.; n]; n];
+        for j in 0..n {
+            for i in 0..n {
+                scores[j][i] = f32::min(d1[j][i], d2[j][i]);
+            }
+        }
+        Self::from_scores(n, n, &scores)
+    }
+    /// Jimenez's interleaved gradient noise, ranked within a WxH tile.
+    pub fn interleaved_gradient(w: usize, h: usize) -> Self {
+        let mut scores = vec![vec![0.; w]; h];
+        for j in 0..h {
+            for i in 0..w {
+                let t = 0.06711056 * i as f32 + 0.00583715 * j as f32;
+                scores[j][i] = (52.982918 * t.fract()).fract();
+            }
+        }
+        Self::from_scores(w, h, &scores)
+    }
+    /// Void-and-cluster blue noise, deterministic for a given seed.
+    pub fn bluenoise(w: usize, h: usize, seed: u64) -> Self {
+        let n = w * h;
+        if n == 0 {
+            return Self { w: 0, h: 0, order: vec![] };
+        }
+        let mut field = EnergyField::new(w, h);
+        let mut pattern = vec![false; n];
         // generate the initial binary pattern
-        let mut initial = Self::whitenoise(w, h).binary();
-        loop {
-            let cluster = Self::cluster(&initial, w, h, true);
-            let (x1, y1) = Self::argmax(&cluster, w, h);
-            initial[y1][x1] = false;
-            let void = Self::cluster(&initial, w, h, false);
-            let (x0, y0) = Self::argmax(&void, w, h);
-            if (x0, y0) == (x1, y1) {
-                initial[y1][x1] = true;
-                break;
-            }
-            initial[y0][x0] = true;
+        let ones = usize::max(1, n / 10);
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut indices: Vec<usize> = (0..n).collect();
+        indices.shuffle(&mut rng);
+        for &k in indices[..ones].iter() {
+            pattern[k] = true;
+            field.toggle(k, 1.);
         }
-        let mut order = vec![vec![0; w]; h];
-        let ones: usize = initial.iter().map(
-            |row| row.iter().map(|&b| b as usize).sum::<usize>()
-        ).sum();
-        let mut state = initial.clone();
+        // move points from the tightest clusters into the largest voids
+        for _ in 0..n {
+            let c = field.extreme(&pattern, true);
+            pattern[c] = false;
+            field.toggle(c, -1.);
+            let v = field.extreme(&pattern, false);
+            pattern[v] = true;
+            field.toggle(v, 1.);
+            if v == c { break; }
+        }
+        let mut order = vec![0; n];
+        let (initial, initial_field) = (pattern.clone(), field.clone());
         for rank in (0..ones).rev() {
-            let cluster = Self::cluster(&state, w, h, true);
-            let (x1, y1) = Self::argmax(&cluster, w, h);
-            state[y1][x1] = false;
-            order[y1][x1] = rank;
-        }
-        state = initial;
-        for rank in ones..w*h {
-            let void = Self::cluster(&state, w, h, false);
-            let (x0, y0) = Self::argmax(&void, w, h);
-            state[y0][x0] = true;
-            order[y0][x0] = rank;
+            let c = field.extreme(&pattern, true);
+            pattern[c] = false;
+            field.toggle(c, -1.);
+            order[c] = rank;
+        }
+        pattern = initial;
+        field = initial_field;
+        for rank in ones..n {
+            let v = field.extreme(&pattern, false);
+            pattern[v] = true;
+            field.toggle(v, 1.);
+            order[v] = rank;
         }
+        let order = order.chunks(w).map(|row| row.to_vec()).collect();
         Self { w, h, order }
     }
 }
+
+/// Gaussian energy of a binary pattern on a torus, updated point by point.
+#[derive(Clone)]
+struct EnergyField {
+    w: usize,
+    h: usize,
+    kernel: Vec<(usize, usize, f32)>,
+    energy: Vec<f32>
+}
+impl EnergyField {
+    fn new(w: usize, h: usize) -> Self {
+        let s: f32 = 1.5;
+        // the kernel is negligible further away
+        let radius = (4. * s).ceil() as i32;
+        fn offsets(n: usize, radius: i32) -> Vec<i32> {
+            let n = n as i32;
+            if 2 * radius + 1 >= n {
+                return (0..n).map(|d| if d > n / 2 { d - n } else { d }).collect();
+            }
+            return (-radius..=radius).collect();
+        }
+        let mut kernel = vec![];
+        for &dy in offsets(h, radius).iter() {
+            for &dx in offsets(w, radius).iter() {
+                let dr = f32::hypot(dx as f32, dy as f32);
+                let t = dr / s;
+                let x = dx.rem_euclid(w as i32) as usize;
+                let y = dy.rem_euclid(h as i32) as usize;
+                kernel.push((x, y, f32::exp(-t.powi(2) / 2.)));
+            }
+        }
+        Self { w, h, kernel, energy: vec![0.; w * h] }
+    }
+    fn toggle(&mut self, k: usize, sign: f32) {
+        let (x, y) = (k % self.w, k / self.w);
+        for &(dx, dy, v) in self.kernel.iter() {
+            let i = (x + dx) % self.w;
+            let j = (y + dy) % self.h;
+            self.energy[j * self.w + i] += sign * v;
+        }
+    }
+    /// The tightest cluster among set points or the largest void among unset ones.
+    fn extreme(&self, pattern: &Vec<bool>, set: bool) -> usize {
+        let mut best = None;
+        for k in 0..pattern.len() {
+            if pattern[k] != set { continue; }
+            let v = if set { self.energy[k] } else { -self.energy[k] };
+            match best {
+                Some((_, b)) if b >= v => {}
+                _ => { best = Some((k, v)); }
+            }
+        }
+        return best.map(|(k, _)| k).unwrap_or(0);
+    }
+}
 impl ThresholdStructure for ThresholdMatrix {
     fn at(&self, x: usize, y: usize) -> f32 {
-        let max = self.w * self.h - 1;
+        // Empty and single cell matrices don't perturb anything.
+        let max = (self.w * self.h).saturating_sub(1);
         if max == 0 { return 0.; }
         let i = x.cyclic_clip(self.w);
         let j = y.cyclic_clip(self.h);
@@ -160,13 +283,30 @@ impl ThresholdStructure for ThresholdMatrix {
     }
 }
 
+/// How ordered dithering perturbs colours by the threshold, with the amplitude factor.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Spread {
+    /// Along lightness, by the palette's lightness range divided by its size.
+    Lightness(f32),
+    /// Towards the second nearest palette colour, by the distance between
+    /// the two nearest ones, so that a colour between them is mixed from both.
+    Chromatic(f32)
+}
+impl Default for Spread {
+    fn default() -> Self {
+        Self::Lightness(1.)
+    }
+}
+
 pub struct OrderedDither {}
 impl OrderedDither {
     pub fn dither<T: ThresholdStructure, P: AsRef<Palette>>
-            (input: PlotData<CAM16UCS>, palette: P, threshold: &T) -> PlotData<RGB255> {
-        let J_min: f32 = palette.as_ref().cam16.iter().map(|c| PackedF32(c.J)).min().unwrap().0;
-        let J_max: f32 = palette.as_ref().cam16.iter().map(|c| PackedF32(c.J)).max().unwrap().0;
-        let J_spread = (J_max - J_min) / palette.as_ref().n as f32;
+            (input: PlotData<CAM16UCS>, palette: P, threshold: &T,
+             spread: Spread) -> PlotData<RGB255> {
+        let palette = palette.as_ref();
+        let J_min: f32 = palette.cam16.iter().map(|c| PackedF32(c.J)).min().unwrap().0;
+        let J_max: f32 = palette.cam16.iter().map(|c| PackedF32(c.J)).max().unwrap().0;
+        let J_spread = (J_max - J_min) / palette.n as f32;
         let h = input.data.len();
         let w = input.data[0].len();
         let mut output = vec![vec![None; w]; h];
@@ -176,59 +316,712 @@ impl OrderedDither {
                     Some(x) => { x }
                     None => { continue; }
                 };
-                c.J += J_spread * (threshold.at(i, j) - 0.5);
-                output[j][i] = Some(palette.as_ref().nearest(c));
+                let t = threshold.at(i, j) - 0.5;
+                match spread {
+                    Spread::Lightness(k) => {
+                        c.J += k * J_spread * t;
+                    }
+                    Spread::Chromatic(k) => {
+                        let first = palette.nearest_index(c);
+                        let second = palette.second_nearest_index(c, first);
+                        let (p, q) = (palette.cam16[first], palette.cam16[second]);
+                        // The full difference moves c across the midpoint
+                        // as often as it is close to q.
+                        c.J += k * (q.J - p.J) * t;
+                        c.a += k * (q.a - p.a) * t;
+                        c.b += k * (q.b - p.b) * t;
+                    }
+                }
+                output[j][i] = Some(palette.nearest(c));
+            }
+        }
+        return PlotData::new(output);
+    }
+}
+
+/// Larger mixes are barely distinguishable but slow pattern dithering down.
+pub const PATTERN_MAX_CANDIDATES: usize = 64;
+
+pub struct PatternDither {}
+impl PatternDither {
+    /// Knoll's pattern dithering: for every pixel builds a mix of `n` palette
+    /// colours whose average approximates it, sorts the mix by lightness
+    /// and picks one of its entries according to the threshold.
+    pub fn dither<T: ThresholdStructure, P: AsRef<Palette>>
+            (input: PlotData<CAM16UCS>, palette: P, threshold: &T, n: usize) -> PlotData<RGB255> {
+        let palette = palette.as_ref();
+        let h = input.data.len();
+        let w = input.data[0].len();
+        let mut output = vec![vec![None; w]; h];
+        let mut candidates = Vec::with_capacity(n);
+        for i in 0..w {
+            for j in 0..h {
+                let c = match input.data[j][i] {
+                    Some(x) => { x }
+                    None => { continue; }
+                };
+                candidates.clear();
+                let mut error = (0., 0., 0.);
+                for _ in 0..n {
+                    let mut t = c;
+                    t.J += error.0;
+                    t.a += error.1;
+                    t.b += error.2;
+                    let k = palette.nearest_index(t);
+                    let p = palette.cam16[k];
+                    error.0 += c.J - p.J;
+                    error.1 += c.a - p.a;
+                    error.2 += c.b - p.b;
+                    candidates.push(k);
+                }
+                candidates.sort_by_key(|&k| PackedF32(palette.cam16[k].J));
+                let index = (threshold.at(i, j) * (n - 1) as f32).round() as usize;
+                output[j][i] = Some(palette.rgb[candidates[index.min(n - 1)]]);
+            }
+        }
+        return PlotData::new(output);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DiffusionKernel {
+    FloydSteinberg,
+    Atkinson,
+    JarvisJudiceNinke,
+    Stucki,
+    Sierra,
+    Burkes
+}
+impl DiffusionKernel {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::FloydSteinberg => { "Floyd-Steinberg" }
+            Self::Atkinson => { "Atkinson" }
+            Self::JarvisJudiceNinke => { "Jarvis-Judice-Ninke" }
+            Self::Stucki => { "Stucki" }
+            Self::Sierra => { "Sierra" }
+            Self::Burkes => { "Burkes" }
+        }
+    }
+    /// Error fractions sent to pixels at (dx, dy) when scanning left to right.
+    pub fn weights(self) -> Vec<(i32, i32, f32)> {
+        let (divisor, data): (f32, &[(i32, i32, f32)]) = match self {
+            Self::FloydSteinberg => { (16., &[
+                (1, 0, 7.),
+                (-1, 1, 3.), (0, 1, 5.), (1, 1, 1.)
+            ]) }
+            // Only 3/4 of the error is diffused.
+            Self::Atkinson => { (8., &[
+                (1, 0, 1.), (2, 0, 1.),
+                (-1, 1, 1.), (0, 1, 1.), (1, 1, 1.),
+                (0, 2, 1.)
+            ]) }
+            Self::JarvisJudiceNinke => { (48., &[
+                (1, 0, 7.), (2, 0, 5.),
+                (-2, 1, 3.), (-1, 1, 5.), (0, 1, 7.), (1, 1, 5.), (2, 1, 3.),
+                (-2, 2, 1.), (-1, 2, 3.), (0, 2, 5.), (1, 2, 3.), (2, 2, 1.)
+            ]) }
+            Self::Stucki => { (42., &[
+                (1, 0, 8.), (2, 0, 4.),
+                (-2, 1, 2.), (-1, 1, 4.), (0, 1, 8.), (1, 1, 4.), (2, 1, 2.),
+                (-2, 2, 1.), (-1, 2, 2.), (0, 2, 4.), (1, 2, 2.), (2, 2, 1.)
+            ]) }
+            Self::Sierra => { (32., &[
+                (1, 0, 5.), (2, 0, 3.),
+                (-2, 1, 2.), (-1, 1, 4.), (0, 1, 5.), (1, 1, 4.), (2, 1, 2.),
+                (-1, 2, 2.), (0, 2, 3.), (1, 2, 2.)
+            ]) }
+            Self::Burkes => { (32., &[
+                (1, 0, 8.), (2, 0, 4.),
+                (-2, 1, 2.), (-1, 1, 4.), (0, 1, 8.), (1, 1, 4.), (2, 1, 2.)
+            ]) }
+        };
+        return data.iter().map(|&(dx, dy, w)| (dx, dy, w / divisor)).collect();
+    }
+}
+
+pub struct ErrorDiffusion {}
+impl ErrorDiffusion {
+    /// Diffuses the quantisation error in J'a'b'.
+    ///
+    /// Every pixel gathers the error from the already processed pixels
+    /// that the kernel reaches it from, always in the same order.
+    /// With `serpentine`, odd rows are scanned right to left with
+    /// the kernel mirrored. The diffused error is scaled by `strength`.
+    pub fn dither<P: AsRef<Palette>>
+            (input: PlotData<CAM16UCS>, palette: P,
+             kernel: DiffusionKernel, serpentine: bool, strength: f32) -> PlotData<RGB255> {
+        let palette = palette.as_ref();
+        let weights = kernel.weights();
+        let depth = Self::depth(&weights);
+        let h = input.data.len();
+        let w = input.data[0].len();
+        // Errors of the last `depth` rows, indexed by `y % depth`.
+        let mut errors = vec![vec![[0f32; 3]; w]; depth];
+        let mut output = vec![vec![None; w]; h];
+        for j in 0..h {
+            for k in 0..w {
+                let i = Self::scan_x(k, j, w, serpentine);
+                errors[j % depth][i] = [0.; 3];
+                let c = match input.data[j][i] {
+                    Some(x) => { x }
+                    None => { continue; }
+                };
+                let (n, e) = Self::diffuse(c, i, j, w, &weights, serpentine, strength, palette,
+                                           |ii, jj| errors[jj % depth][ii]);
+                errors[j % depth][i] = e;
+                output[j][i] = Some(palette.rgb[n]);
+            }
+        }
+        return PlotData::new(output);
+    }
+    /// Number of rows the kernel reaches, including the current one.
+    fn depth(weights: &Vec<(i32, i32, f32)>) -> usize {
+        weights.iter().map(|&(_, dy, _)| dy as usize).max().unwrap_or(0) + 1
+    }
+    /// Column of the `k`-th pixel scanned in row `j`.
+    fn scan_x(k: usize, j: usize, w: usize, serpentine: bool) -> usize {
+        if serpentine && j % 2 == 1 { w - 1 - k } else { k }
+    }
+    /// Gathers the error of processed pixels into `c` at (i, j) and quantises it,
+    /// returning the palette index and the error to diffuse.
+    #[allow(clippy::too_many_arguments)]
+    fn diffuse<F: Fn(usize, usize) -> [f32; 3]>
+            (mut c: CAM16UCS, i: usize, j: usize, w: usize, weights: &Vec<(i32, i32, f32)>,
+             serpentine: bool, strength: f32, palette: &Palette, error: F) -> (usize, [f32; 3]) {
+        for &(dx, dy, weight) in weights.iter() {
+            if dy as usize > j { continue; }
+            let jj = j - dy as usize;
+            let reversed = serpentine && jj % 2 == 1;
+            let ii = if reversed { i as i32 + dx } else { i as i32 - dx };
+            if ii < 0 || ii >= w as i32 { continue; }
+            let e = error(ii as usize, jj);
+            c.J += e[0] * weight;
+            c.a += e[1] * weight;
+            c.b += e[2] * weight;
+        }
+        let n = palette.nearest_index(c);
+        let p = palette.cam16[n];
+        let e = [
+            (c.J - p.J) * strength,
+            (c.a - p.a) * strength,
+            (c.b - p.b) * strength
+        ];
+        return (n, e);
+    }
+    /// Same as `dither`, but rows are processed by `threads` workers in a wavefront:
+    /// every pixel waits until the pixels it gathers from are done.
+    /// Pixels are converted from `source` as they are reached.
+    pub fn dither_parallel<C: Copy + Sync>
+            (source: &Vec<Vec<Option<C>>>, lut: &CAM16Lut, palette: &Palette,
+             kernel: DiffusionKernel, serpentine: bool, strength: f32,
+             threads: usize) -> PlotData<RGB255> where RGB1: From<C> {
+        use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+        let weights = kernel.weights();
+        let depth = Self::depth(&weights);
+        let reach = weights.iter()
+            .filter(|&&(_, dy, _)| dy > 0)
+            .map(|&(dx, _, _)| dx.unsigned_abs() as usize)
+            .max().unwrap_or(0);
+        let h = source.len();
+        let w = source[0].len();
+        // Rows are reused once every row reading them is done.
+        let slots = depth + threads;
+        let errors: Vec<AtomicU32> = (0..slots * w * 3).map(|_| AtomicU32::new(0)).collect();
+        let progress: Vec<AtomicUsize> = (0..h).map(|_| AtomicUsize::new(0)).collect();
+        let wait = |j: usize, count: usize| {
+            while progress[j].load(Ordering::Acquire) < count {
+                std::thread::yield_now();
             }
+        };
+
+        let rows = crossbeam_utils::thread::scope(|s| {
+            let handles: Vec<_> = (0..threads).map(|t| {
+                let (errors, progress, weights) = (&errors, &progress, &weights);
+                s.spawn(move |_| {
+                    let mut rows = vec![];
+                    for j in (t..h).step_by(threads) {
+                        if j > threads {
+                            wait(j - threads - 1, w);
+                        }
+                        let slot = (j % slots) * w * 3;
+                        let mut row = vec![None; w];
+                        for k in 0..w {
+                            let i = Self::scan_x(k, j, w, serpentine);
+                            for dy in 1..usize::min(depth, j + 1) {
+                                let jj = j - dy;
+                                let needed = if serpentine && jj % 2 == 1 {
+                                    w - i.saturating_sub(reach)
+                                } else {
+                                    usize::min(i + reach + 1, w)
+                                };
+                                wait(jj, needed);
+                            }
+                            let e = match source[j][i] {
+                                Some(rgb) => {
+                                    let c = lut.of(RGB1::from(rgb));
+                                    let read = |ii: usize, jj: usize| {
+                                        let at = (jj % slots) * w * 3 + ii * 3;
+                                        [
+                                            f32::from_bits(errors[at].load(Ordering::Relaxed)),
+                                            f32::from_bits(errors[at + 1].load(Ordering::Relaxed)),
+                                            f32::from_bits(errors[at + 2].load(Ordering::Relaxed))
+                                        ]
+                                    };
+                                    let (n, e) = Self::diffuse(c, i, j, w, weights, serpentine,
+                                                               strength, palette, read);
+                                    row[i] = Some(palette.rgb[n]);
+                                    e
+                                }
+                                None => { [0.; 3] }
+                            };
+                            for m in 0..3 {
+                                errors[slot + i * 3 + m].store(e[m].to_bits(), Ordering::Relaxed);
+                            }
+                            progress[j].store(k + 1, Ordering::Release);
+                        }
+                        rows.push((j, row));
+                    }
+                    rows
+                })
+            }).collect();
+            handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect::<Vec<_>>()
+        }).unwrap();
+
+        let mut output = vec![vec![]; h];
+        for (j, row) in rows {
+            output[j] = row;
         }
         return PlotData::new(output);
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LineDirection {
+    Horizontal,
+    Vertical,
+    Diagonal
+}
+impl LineDirection {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "horizontal" => { Some(Self::Horizontal) }
+            "vertical" => { Some(Self::Vertical) }
+            "diagonal" => { Some(Self::Diagonal) }
+            _ => { None }
+        }
+    }
+}
+
+#[derive(Clone)]
 pub enum DitheringMethod {
     None,
     Bayer(u32),
-    WhiteNoise(usize, usize),
-    BlueNoise(usize, usize)
+    WhiteNoise(usize, usize, u64),
+    BlueNoise(usize, usize, u64),
+    Dispersed(usize),
+    Halftone(usize),
+    Lines(usize, LineDirection),
+    CrossHatch(usize),
+    InterleavedGradient(usize, usize),
+    Custom(ThresholdMatrix),
+    Pattern(u32),
+    ErrorDiffusion {
+        kernel: DiffusionKernel,
+        serpentine: bool,
+        strength: f32
+    }
+}
+impl DitheringMethod {
+    pub fn name(&self) -> String {
+        match self {
+            Self::None => { "none".into() }
+            Self::Bayer(n) => { format!("bayer {}x{}", 2u32.pow(*n), 2u32.pow(*n)) }
+            Self::WhiteNoise(w, h, _) => { format!("white noise {}x{}", w, h) }
+            Self::BlueNoise(w, h, _) => { format!("blue noise {}x{}", w, h) }
+            Self::Dispersed(n) => { format!("dispersed {}x{}", n, n) }
+            Self::Halftone(n) => { format!("halftone {}x{}", n, n) }
+            Self::Lines(n, direction) => {
+                format!("{} lines {}", format!("{:?}", direction).to_lowercase(), n)
+            }
+            Self::CrossHatch(n) => { format!("crosshatch {}", n) }
+            Self::InterleavedGradient(w, h) => { format!("ign {}x{}", w, h) }
+            Self::Custom(matrix) => {
+                let (w, h) = matrix.size();
+                format!("custom {}x{}", w, h)
+            }
+            Self::Pattern(n) => { format!("pattern {}x{}", 2u32.pow(*n), 2u32.pow(*n)) }
+            Self::ErrorDiffusion { kernel, .. } => { kernel.name().to_lowercase() }
+        }
+    }
+    /// Replaces the seed of random threshold matrices.
+    pub fn with_seed(self, seed: u64) -> Self {
+        match self {
+            Self::WhiteNoise(w, h, _) => { Self::WhiteNoise(w, h, seed) }
+            Self::BlueNoise(w, h, _) => { Self::BlueNoise(w, h, seed) }
+            method => { method }
+        }
+    }
 }
 impl Default for DitheringMethod {
     fn default() -> Self {
-        Self::BlueNoise(14, 14)
+        Self::BlueNoise(14, 14, BLUENOISE_SEED)
     }
 }
 
-// TODO: cache
+/// Seed of the blue noise matrices unless specified.
+pub const BLUENOISE_SEED: u64 = 0;
+
 pub struct Ditherer {}
 impl Ditherer {
-    pub fn dither<P: AsRef<Palette>>
-            (input: PlotData<CAM16UCS>, palette: P, method: DitheringMethod, verbose: bool)
-                -> PlotData<RGB255> {
-        match method {
-            DitheringMethod::None => {
-                let matrix = ThresholdMatrix::bayer(0);
-                if verbose { eprintln!("Dithering in progress...") }
-                OrderedDither::dither(input, palette, &matrix)
-            }
-            DitheringMethod::Bayer(n) => {
+    /// Side of the square tiles dithered in parallel.
+    pub const TILE: usize = 128;
+
+    /// Creates the threshold matrix of ordered and pattern dithering methods.
+    pub fn threshold_matrix(method: &DitheringMethod, cacher: &mut BigCacher, verbose: bool)
+                -> Option<ThresholdMatrix> {
+        let matrix = match *method {
+            DitheringMethod::None => { ThresholdMatrix::bayer(0) }
+            DitheringMethod::Bayer(n) | DitheringMethod::Pattern(n) => {
                 if verbose {
                     eprintln!("Creating threshold matrix (Bayer, {}x{})", 2u32.pow(n), 2u32.pow(n))
                 }
-                let matrix = ThresholdMatrix::bayer(n);
-                if verbose { eprintln!("Dithering in progress...") }
-                OrderedDither::dither(input, palette, &matrix)
-            }
-            DitheringMethod::WhiteNoise(w, h) => {
-                if verbose { eprintln!("Creating threshold matrix (White noise, {}x{})", w, h) }
-                let matrix = ThresholdMatrix::whitenoise(w, h);
-                if verbose { eprintln!("Dithering in progress...") }
-                OrderedDither::dither(input, palette, &matrix)
-            }
-            DitheringMethod::BlueNoise(w, h) => {
-                if verbose { eprintln!("Creating threshold matrix (Blue noise, {}x{})", w, h) }
-                let matrix = ThresholdMatrix::bluenoise(w, h);
-                if verbose { eprintln!("Dithering in progress...") }
-                OrderedDither::dither(input, palette, &matrix)
+                ThresholdMatrix::bayer(n)
+            }
+            DitheringMethod::WhiteNoise(w, h, seed) => {
+                if verbose {
+                    eprintln!("Creating threshold matrix (White noise, {}x{}, seed {})", w, h, seed)
+                }
+                ThresholdMatrix::whitenoise(w, h, seed)
             }
+            DitheringMethod::BlueNoise(w, h, seed) => {
+                match cacher.get_bluenoise(w, h, seed) {
+                    Some(matrix) => {
+                        if verbose {
+                            eprintln!("Using cached threshold matrix (Blue noise, {}x{}, seed {})",
+                                      w, h, seed)
+                        }
+                        matrix.clone()
+                    }
+                    None => {
+                        if verbose {
+                            eprintln!("Creating threshold matrix (Blue noise, {}x{}, seed {})",
+                                      w, h, seed)
+                        }
+                        let matrix = ThresholdMatrix::bluenoise(w, h, seed);
+                        cacher.set_bluenoise(w, h, seed, matrix.clone());
+                        if let Err(e) = cacher.save() {
+                            if verbose { eprintln!("Error saving cache: {}", e); }
+                        }
+                        matrix
+                    }
+                }
+            }
+            DitheringMethod::Dispersed(n) => {
+                if verbose { eprintln!("Creating threshold matrix (Dispersed dot, {}x{})", n, n) }
+                ThresholdMatrix::dispersed(n)
+            }
+            DitheringMethod::Halftone(n) => {
+                if verbose { eprintln!("Creating threshold matrix (Halftone, {}x{})", n, n) }
+                ThresholdMatrix::halftone(n)
+            }
+            DitheringMethod::Lines(n, direction) => {
+                if verbose {
+                    eprintln!("Creating threshold matrix ({:?} lines, {}x{})", direction, n, n)
+                }
+                ThresholdMatrix::lines(n, direction)
+            }
+            DitheringMethod::CrossHatch(n) => {
+                if verbose { eprintln!("Creating threshold matrix (Cross-hatch, {}x{})", n, n) }
+                ThresholdMatrix::crosshatch(n)
+            }
+            DitheringMethod::InterleavedGradient(w, h) => {
+                if verbose {
+                    eprintln!("Creating threshold matrix (Interleaved gradient noise, {}x{})", w, h)
+                }
+                ThresholdMatrix::interleaved_gradient(w, h)
+            }
+            DitheringMethod::Custom(ref matrix) => { matrix.clone() }
+            DitheringMethod::ErrorDiffusion { .. } => { return None; }
+        };
+        return Some(matrix);
+    }
+    pub fn dither<P: AsRef<Palette>>
+            (input: PlotData<CAM16UCS>, palette: P, method: DitheringMethod, spread: Spread,
+             cacher: &mut BigCacher, verbose: bool) -> PlotData<RGB255> {
+        let matrix = Self::threshold_matrix(&method, cacher, verbose);
+        if verbose { Self::announce(&method) }
+        return Self::dither_region(input, palette, &method, spread, matrix.as_ref(), 0, 0);
+    }
+    pub fn announce(method: &DitheringMethod) {
+        match *method {
+            DitheringMethod::Pattern(n) => {
+                let candidates = usize::min(4usize.pow(n), PATTERN_MAX_CANDIDATES);
+                eprintln!("Dithering in progress (pattern, {} candidates)...", candidates)
+            }
+            DitheringMethod::ErrorDiffusion { kernel, .. } => {
+                eprintln!("Dithering in progress ({} error diffusion)...", kernel.name())
+            }
+            _ => { eprintln!("Dithering in progress...") }
+        }
+    }
+    /// Same as `dither`, but the image is converted from `source` and dithered
+    /// by `threads` workers, in tiles or, for error diffusion, in a row wavefront.
+    /// The result does not depend on the number of threads.
+    /// `matrix` must come from `threshold_matrix` for the same method.
+    #[allow(clippy::too_many_arguments)]
+    pub fn dither_parallel<C: Copy + Sync>
+            (source: &Vec<Vec<Option<C>>>, lut: &CAM16Lut, palette: &Palette,
+             method: &DitheringMethod, matrix: Option<&ThresholdMatrix>, spread: Spread,
+             threads: usize, verbose: bool) -> PlotData<RGB255> where RGB1: From<C> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let threads = usize::max(threads, 1);
+        if verbose { Self::announce(method) }
+        if let DitheringMethod::ErrorDiffusion { kernel, serpentine, strength } = *method {
+            return ErrorDiffusion::dither_parallel(source, lut, palette, kernel, serpentine,
+                                                   strength, threads);
+        }
+        let h = source.len();
+        let w = source[0].len();
+        let mut tiles = vec![];
+        for y0 in (0..h).step_by(Self::TILE) {
+            for x0 in (0..w).step_by(Self::TILE) {
+                tiles.push((x0, y0));
+            }
+        }
+        let next = AtomicUsize::new(0);
+        let done = crossbeam_utils::thread::scope(|s| {
+            let handles: Vec<_> = (0..threads).map(|_| {
+                let (tiles, next) = (&tiles, &next);
+                s.spawn(move |_| {
+                    let mut done = vec![];
+                    loop {
+                        let k = next.fetch_add(1, Ordering::Relaxed);
+                        if k >= tiles.len() { break; }
+                        let (x0, y0) = tiles[k];
+                        let (x1, y1) = (usize::min(x0 + Self::TILE, w), usize::min(y0 + Self::TILE, h));
+                        let tile = source[y0..y1].iter()
+                            .map(|row| row[x0..x1].iter()
+                                .map(|opt| opt.map(|rgb| lut.of(RGB1::from(rgb))))
+                                .collect())
+                            .collect();
+                        let result = Self::dither_region(PlotData::new(tile), palette, method,
+                                                         spread, matrix, x0, y0);
+                        done.push((x0, y0, result));
+                    }
+                    done
+                })
+            }).collect();
+            handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect::<Vec<_>>()
+        }).unwrap();
+
+        let mut output = vec![vec![None; w]; h];
+        for (x0, y0, result) in done {
+            for (j, row) in result.data.into_iter().enumerate() {
+                output[y0 + j][x0..x0 + row.len()].copy_from_slice(&row);
+            }
+        }
+        return PlotData::new(output);
+    }
+    /// Dithers a region of a larger image starting at (x0, y0), so that
+    /// thresholds stay aligned with the whole image.
+    /// `matrix` must come from `threshold_matrix` for the same method.
+    pub fn dither_region<P: AsRef<Palette>>
+            (input: PlotData<CAM16UCS>, palette: P, method: &DitheringMethod, spread: Spread,
+             matrix: Option<&ThresholdMatrix>, x0: usize, y0: usize) -> PlotData<RGB255> {
+        match (method, matrix) {
+            (&DitheringMethod::ErrorDiffusion { kernel, serpentine, strength }, _) => {
+                ErrorDiffusion::dither(input, palette, kernel, serpentine, strength)
+            }
+            (&DitheringMethod::Pattern(n), Some(matrix)) => {
+                let candidates = usize::min(4usize.pow(n), PATTERN_MAX_CANDIDATES);
+                let threshold = ShiftedThreshold { inner: matrix, dx: x0, dy: y0 };
+                PatternDither::dither(input, palette, &threshold, candidates)
+            }
+            (_, Some(matrix)) => {
+                let threshold = ShiftedThreshold { inner: matrix, dx: x0, dy: y0 };
+                OrderedDither::dither(input, palette, &threshold, spread)
+            }
+            (_, None) => {
+                OrderedDither::dither(input, palette, &ThresholdMatrix::bayer(0), Spread::default())
+            }
+        }
+    }
+}
+
+/// A threshold structure read from an offset position.
+pub struct ShiftedThreshold<'a, T: ThresholdStructure> {
+    pub inner: &'a T,
+    pub dx: usize,
+    pub dy: usize
+}
+impl<'a, T: ThresholdStructure> ThresholdStructure for ShiftedThreshold<'a, T> {
+    fn at(&self, x: usize, y: usize) -> f32 {
+        self.inner.at(x + self.dx, y + self.dy)
+    }
+}
+
+pub struct DitherQuality {
+    pub mean: f32,
+    pub p95: f32
+}
+impl DitherQuality {
+    /// Blur radius approximating how dithering is perceived at a normal viewing distance.
+    pub const SIGMA: f32 = 1.;
+
+    /// The result blurred in linear light, as it is seen from a distance;
+    /// transparent pixels are ignored and stay transparent.
+    pub fn blur(result: &PlotData<RGB255>) -> Vec<Vec<Option<RGB1>>> {
+        let h = result.data.len();
+        let w = if h > 0 { result.data[0].len() } else { 0 };
+        let linear: Vec<Vec<Option<[f32; 3]>>> = result.data.iter().map(
+            |row| row.iter().map(
+                |opt| opt.map(|rgb| {
+                    let c = RGB1::from(rgb);
+                    [ungamma(c.r), ungamma(c.g), ungamma(c.b)]
+                })
+            ).collect()
+        ).collect();
+        let radius = (3. * Self::SIGMA).ceil() as i32;
+        let kernel: Vec<f32> = (-radius..=radius)
+            .map(|d| f32::exp(-(d as f32 / Self::SIGMA).powi(2) / 2.))
+            .collect();
+        // Separable blur, ignoring transparent pixels
+        let blur = |data: &Vec<Vec<Option<[f32; 3]>>>, horizontal: bool| {
+            let mut out = vec![vec![None; w]; h];
+            for j in 0..h {
+                for i in 0..w {
+                    if data[j][i].is_none() { continue; }
+                    let mut sum = [0.; 3];
+                    let mut weight = 0.;
+                    for d in -radius..=radius {
+                        let (x, y) = if horizontal {
+                            (i as i32 + d, j as i32)
+                        } else {
+                            (i as i32, j as i32 + d)
+                        };
+                        if x < 0 || y < 0 || x >= w as i32 || y >= h as i32 { continue; }
+                        if let Some(c) = data[y as usize][x as usize] {
+                            let k = kernel[(d + radius) as usize];
+                            for ch in 0..3 {
+                                sum[ch] += k * c[ch];
+                            }
+                            weight += k;
+                        }
+                    }
+                    out[j][i] = Some([sum[0] / weight, sum[1] / weight, sum[2] / weight]);
+                }
+            }
+            return out;
+        };
+        return blur(&blur(&linear, true), false).iter()
+            .map(|row| row.iter()
+                .map(|px| px.map(|c| RGB1::new(gamma(c[0]), gamma(c[1]), gamma(c[2]))))
+                .collect())
+            .collect();
+    }
+
+    /// Compares the result blurred in linear light against the source in CAM16UCS.
+    pub fn measure(source: &PlotData<CAM16UCS>, result: &PlotData<RGB255>,
+                   ill: &CAT16Illuminant) -> Self {
+        let h = result.data.len();
+        let w = if h > 0 { result.data[0].len() } else { 0 };
+        let blurred = Self::blur(result);
+        let mut errors = vec![];
+        for j in 0..h {
+            for i in 0..w {
+                if let (Some(rgb), Some(x)) = (blurred[j][i], source.data[j][i]) {
+                    let cam16 = CAM16UCS::of(CIEXYZ::from(rgb), ill);
+                    errors.push(CAM16UCS::dist(&cam16, &x));
+                }
+            }
+        }
+        if errors.is_empty() {
+            return Self { mean: 0., p95: 0. };
+        }
+        let mean = errors.iter().sum::<f32>() / errors.len() as f32;
+        errors.sort_by_key(|&e| PackedF32(e));
+        let k = ((0.95 * errors.len() as f32).ceil() as usize).clamp(1, errors.len()) - 1;
+        return Self { mean, p95: errors[k] };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A gradient with noise and a few transparent pixels, ragged against the tiles.
+    fn image() -> Vec<Vec<Option<RGB255>>> {
+        let (w, h) = (Ditherer::TILE * 2 + 37, Ditherer::TILE + 21);
+        let mut rng = StdRng::seed_from_u64(42);
+        (0..h).map(|y| (0..w).map(|x| {
+            if (x * 7 + y * 3) % 97 == 0 { return None; }
+            let noise: u8 = rand::Rng::gen_range(&mut rng, 0..32);
+            Some(RGB255::new((x * 255 / w) as u8, (y * 255 / h) as u8, noise.wrapping_mul(5)))
+        }).collect()).collect()
+    }
+
+    fn palette(ill: &CAT16Illuminant) -> Palette {
+        let rgb = [0x000000, 0xffffff, 0x7f7f7f, 0xcc3322, 0x2255aa, 0x44aa33, 0xeedd44]
+            .iter()
+            .map(|&x: &u32| RGB255::new((x >> 16) as u8, (x >> 8) as u8, x as u8))
+            .collect();
+        Palette::new(rgb, ill, false)
+    }
+
+    fn assert_parallel_matches_serial(method: DitheringMethod) {
+        let ill = CAT16Illuminant::new(CIExy::from_T(5500.));
+        let lut = CAM16Lut::new(&ill);
+        let palette = palette(&ill);
+        let source = image();
+        let input = PlotData::new(source.iter()
+            .map(|row| row.iter().map(|px| px.map(|c| lut.of(c.into()))).collect())
+            .collect());
+        let mut cacher = BigCacher::new();
+        let matrix = Ditherer::threshold_matrix(&method, &mut cacher, false);
+        let serial = Ditherer::dither(input, &palette, method.clone(), Spread::default(),
+                                      &mut cacher, false);
+        for &threads in [1, 2, 7].iter() {
+            let parallel = Ditherer::dither_parallel(&source, &lut, &palette, &method,
+                                                     matrix.as_ref(), Spread::default(),
+                                                     threads, false);
+            assert!(parallel.data == serial.data, "{} differs with {} threads",
+                    method.name(), threads);
+        }
+    }
+
+    #[test]
+    fn parallel_error_diffusion_matches_serial() {
+        let kernels = [
+            DiffusionKernel::FloydSteinberg, DiffusionKernel::Atkinson,
+            DiffusionKernel::JarvisJudiceNinke, DiffusionKernel::Stucki,
+            DiffusionKernel::Sierra, DiffusionKernel::Burkes
+        ];
+        for &kernel in kernels.iter() {
+            for &serpentine in [false, true].iter() {
+                assert_parallel_matches_serial(
+                    DitheringMethod::ErrorDiffusion { kernel, serpentine, strength: 1. }
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_ordered_matches_serial() {
+        // 6 does not divide the tile size, so thresholds must be aligned across tiles.
+        assert_parallel_matches_serial(DitheringMethod::Halftone(6));
+    }
+
+    #[test]
+    fn empty_matrices_dont_panic() {
+        let matrices = [
+            ThresholdMatrix::bluenoise(0, 0, BLUENOISE_SEED),
+            ThresholdMatrix::bluenoise(0, 5, BLUENOISE_SEED),
+            ThresholdMatrix::whitenoise(4, 0, 1),
+            ThresholdMatrix::interleaved_gradient(0, 4)
+        ];
+        for matrix in matrices.iter() {
+            assert_eq!(matrix.at(3, 2), 0.);
         }
     }
 }
@@ -0,0 +1,199 @@
+use crate::colour::*;
+use crate::graph::ImageGraph;
+use crate::loader::LoadedPalette;
+use crate::util::PackedF32;
+
+use std::io::Write;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PaletteFormat {
+    Hex,
+    Gpl,
+    Jasc,
+    PaintNet,
+    Ase,
+    Png,
+    PngScaled
+}
+impl PaletteFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "hex" => { Some(Self::Hex) }
+            "gpl" => { Some(Self::Gpl) }
+            "pal" => { Some(Self::Jasc) }
+            "txt" => { Some(Self::PaintNet) }
+            "ase" => { Some(Self::Ase) }
+            "png" => { Some(Self::Png) }
+            "png8" => { Some(Self::PngScaled) }
+            _ => { None }
+        }
+    }
+    /// Guesses the format from the file extension.
+    pub fn from_filename(filename: &str) -> Option<Self> {
+        let ext = std::path::Path::new(filename).extension()?.to_str()?;
+        Self::from_name(&ext.to_lowercase())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PaletteOrder {
+    Lightness,
+    Hue,
+    Chroma
+}
+impl PaletteOrder {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "lightness" => { Some(Self::Lightness) }
+            "hue" => { Some(Self::Hue) }
+            "chroma" => { Some(Self::Chroma) }
+            _ => { None }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ExportError {
+    UnknownFormat(String),
+    FileWrite(std::io::Error),
+    ImageEncoding(image::ImageError)
+}
+impl std::fmt::Display for ExportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownFormat(ref s) => { write!(f, "Unknown output format: {}", s) }
+            Self::FileWrite(ref e) => { e.fmt(f) }
+            Self::ImageEncoding(ref e) => { e.fmt(f) }
+        }
+    }
+}
+
+/// Removes repeated colours keeping the first occurrence.
+pub fn dedup_colours(colours: &Vec<RGB255>) -> Vec<RGB255> {
+    let mut result = vec![];
+    for &c in colours.iter() {
+        if !result.contains(&c) {
+            result.push(c);
+        }
+    }
+    return result;
+}
+
+/// Sorts colours by a CAM16UCS property; the sort is stable.
+pub fn sort_colours(colours: &Vec<RGB255>, order: PaletteOrder, ill: &CAT16Illuminant)
+            -> Vec<RGB255> {
+    use std::f32::consts::PI;
+    let mut keyed: Vec<(f32, RGB255)> = colours.iter()
+        .map(|&rgb| {
+            let c = CAM16UCS::of(CIEXYZ::from(rgb), ill);
+            let k = match order {
+                PaletteOrder::Lightness => { c.J }
+                PaletteOrder::Hue => { f32::atan2(c.b, c.a).rem_euclid(2. * PI) }
+                PaletteOrder::Chroma => { f32::hypot(c.a, c.b) }
+            };
+            (k, rgb)
+        })
+        .collect();
+    keyed.sort_by_key(|&(k, _)| PackedF32(k));
+    return keyed.into_iter().map(|(_, rgb)| rgb).collect();
+}
+
+pub fn export_palette(palette: &LoadedPalette, format: PaletteFormat,
+                      name: &str, filename: &str) -> Result<(), ExportError> {
+    let colours = &palette.colours;
+    let data: Vec<u8> = match format {
+        PaletteFormat::Hex => { to_hex(colours).into_bytes() }
+        PaletteFormat::Gpl => { to_gpl(colours, name).into_bytes() }
+        PaletteFormat::Jasc => { to_jasc(colours).into_bytes() }
+        PaletteFormat::PaintNet => { to_paintnet(colours).into_bytes() }
+        PaletteFormat::Ase => { to_ase(colours) }
+        PaletteFormat::Png => {
+            return save_swatches(palette, 1, filename);
+        }
+        PaletteFormat::PngScaled => {
+            return save_swatches(palette, 8, filename);
+        }
+    };
+    let mut file = std::fs::File::create(filename).map_err(|e| ExportError::FileWrite(e))?;
+    file.write_all(&data).map_err(|e| ExportError::FileWrite(e))
+}
+
+fn to_hex(colours: &Vec<RGB255>) -> String {
+    let mut s = String::new();
+    for c in colours.iter() {
+        s += &format!("{}\n", c.hex());
+    }
+    return s;
+}
+
+fn to_gpl(colours: &Vec<RGB255>, name: &str) -> String {
+    let mut s = String::new();
+    s += "GIMP Palette\n";
+    s += &format!("Name: {}\n", name);
+    s += "#\n";
+    for c in colours.iter() {
+        s += &format!("{:3} {:3} {:3}\t{}\n", c.r, c.g, c.b, c.hex());
+    }
+    return s;
+}
+
+fn to_jasc(colours: &Vec<RGB255>) -> String {
+    let mut s = String::new();
+    s += "JASC-PAL\r\n0100\r\n";
+    s += &format!("{}\r\n", colours.len());
+    for c in colours.iter() {
+        s += &format!("{} {} {}\r\n", c.r, c.g, c.b);
+    }
+    return s;
+}
+
+fn to_paintnet(colours: &Vec<RGB255>) -> String {
+    let mut s = String::new();
+    s += ";paint.net Palette File\n";
+    s += &format!(";Colors: {}\n", colours.len());
+    for c in colours.iter() {
+        s += &format!("FF{}\n", c.hex().to_uppercase());
+    }
+    return s;
+}
+
+/// Adobe Swatch Exchange, one global RGB colour entry per swatch.
+fn to_ase(colours: &Vec<RGB255>) -> Vec<u8> {
+    let mut data = vec![];
+    data.extend_from_slice(b"ASEF");
+    data.extend_from_slice(&1u16.to_be_bytes());
+    data.extend_from_slice(&0u16.to_be_bytes());
+    data.extend_from_slice(&(colours.len() as u32).to_be_bytes());
+    for c in colours.iter() {
+        let name: Vec<u16> = c.hex()
+            .encode_utf16()
+            .chain(std::iter::once(0))
+            .collect();
+        let length = 2 + name.len() * 2 + 4 + 3 * 4 + 2;
+        data.extend_from_slice(&0x0001u16.to_be_bytes());
+        data.extend_from_slice(&(length as u32).to_be_bytes());
+        data.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        for ch in name {
+            data.extend_from_slice(&ch.to_be_bytes());
+        }
+        data.extend_from_slice(b"RGB ");
+        for v in [c.r, c.g, c.b] {
+            data.extend_from_slice(&(v as f32 / 255.).to_be_bytes());
+        }
+        // Normal (not global or spot) colour
+        data.extend_from_slice(&2u16.to_be_bytes());
+    }
+    return data;
+}
+
+fn save_swatches(palette: &LoadedPalette, scale: i32, filename: &str) -> Result<(), ExportError> {
+    let n = palette.colours.len() as i32;
+    let mut graph = ImageGraph::new((n * scale) as u32, scale as u32);
+    if let Some(ref profile) = palette.icc_profile {
+        graph = graph.with_icc_profile(profile.clone());
+    }
+    for i in 0..n {
+        graph.block(i * scale, 0, scale, scale, palette.colours[i as usize]);
+    }
+    graph.save(filename.into()).map_err(|e| ExportError::ImageEncoding(e))
+}
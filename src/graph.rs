@@ -246,6 +246,7 @@ impl ImageGraph {
             y += 1 + font.char_height(chars[i]);
         }
     }
+    #[allow(clippy::too_many_arguments)]
     pub fn plot<F: Fn(f32, f32) -> Option<CAM16UCS>, P: CacheProvider, PR: AsRef<Palette>>
             (&mut self, cacher: &mut P,
              x0: i32, y0: i32, w: i32, h: i32,
@@ -263,6 +264,7 @@ impl ImageGraph {
         };
         self.plot_data(x0, y0, w, h, palette, cacher.get_plot(key, g));
     }
+    #[allow(clippy::too_many_arguments)]
     pub fn plot_polar<F: Fn(f32, f32) -> Option<CAM16UCS>, P: CacheProvider, PR: AsRef<Palette>>
             (&mut self, cacher: &mut P,
              x0: i32, y0: i32, w: i32, h: i32,
@@ -291,11 +293,8 @@ impl ImageGraph {
         let palette = palette.as_ref();
         for i in 0..w {
             for j in 0..h {
-                match data.data[j as usize][i as usize] {
-                    Some(c) => {
-                        self.put_pixel(x0 + i, y0 + j, palette.nearest(c));
-                    }
-                    None => {}
+                if let Some(c) = data.data[j as usize][i as usize] {
+                    self.put_pixel(x0 + i, y0 + j, palette.nearest(c));
                 }
             }
         }
@@ -348,10 +347,12 @@ pub trait GraphProvider<T: GraphPixel>: PixelWriter<T> {
     fn disc(&mut self, x0: i32, y0: i32, d: i32, c: T);
     fn text(&mut self, s: &str, x0: i32, y0: i32, p: TextAnchor, font: &Font, c: T);
     fn vtext(&mut self, s: &str, x0: i32, y0: i32, p: HorizontalTextAnchor, font: &Font, c: T);
+    #[allow(clippy::too_many_arguments)]
     fn plot<F: Fn(f32, f32) -> Option<CAM16UCS>, P: CacheProvider, PR: AsRef<Palette>>
             (&mut self, cacher: &mut P,
              x0: i32, y0: i32, w: i32, h: i32,
              palette: PR, key: &str, f: F);
+    #[allow(clippy::too_many_arguments)]
     fn plot_polar<F: Fn(f32, f32) -> Option<CAM16UCS>, P: CacheProvider, PR: AsRef<Palette>>
             (&mut self, cacher: &mut P,
              x0: i32, y0: i32, w: i32, h: i32,
@@ -514,7 +515,7 @@ impl<'a, T: GraphPixel> GraphHoster<'a, T> {
         return send;
     }
     pub fn process(&mut self) {
-        while self.connections.len() > 0 {
+        while !self.connections.is_empty() {
             let mut select = crossbeam_channel::Select::new();
             for recv in self.connections.iter() {
                 select.recv(recv);
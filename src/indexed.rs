@@ -0,0 +1,238 @@
+use crate::colour::*;
+use crate::cache::PlotData;
+
+use img_parts::{Bytes, png::{Png, PngChunk}};
+
+use std::borrow::Cow;
+use std::io::{BufWriter, Write};
+
+#[derive(Debug)]
+pub enum IndexedError {
+    TooManyColours(usize),
+    NoTransparentIndex,
+    FileWrite(std::io::Error),
+    PngEncoding(png::EncodingError),
+    GifEncoding(gif::EncodingError),
+    PngChunks(img_parts::Error)
+}
+impl std::fmt::Display for IndexedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooManyColours(n) => {
+                write!(f, "Indexed images support up to 256 colours, the palette has {}", n)
+            }
+            Self::NoTransparentIndex => {
+                write!(f, "No palette index is left for transparent pixels")
+            }
+            Self::FileWrite(ref e) => { e.fmt(f) }
+            Self::PngEncoding(ref e) => { e.fmt(f) }
+            Self::GifEncoding(ref e) => { e.fmt(f) }
+            Self::PngChunks(ref e) => { e.fmt(f) }
+        }
+    }
+}
+
+/// An image of indices into a palette of at most 256 colours.
+#[derive(Clone)]
+pub struct IndexedImage {
+    pub w: usize,
+    pub h: usize,
+    pub palette: Vec<RGB255>,
+    /// Index of the extra entry for transparent (or, if not kept, black) pixels.
+    pub transparent: Option<u8>,
+    pub keep_transparency: bool,
+    pub indices: Vec<u8>
+}
+impl IndexedImage {
+    /// Maps every pixel onto its first occurrence in `colours`, keeping their order.
+    /// Pixels that are missing or not in `colours` get an extra entry after them.
+    pub fn from_plot(data: &PlotData<RGB255>, colours: &Vec<RGB255>, keep_transparency: bool)
+                -> Result<Self, IndexedError> {
+        let n = colours.len();
+        if n > 256 {
+            return Err(IndexedError::TooManyColours(n));
+        }
+        let h = data.data.len();
+        let w = if h > 0 { data.data[0].len() } else { 0 };
+        let mut palette = colours.clone();
+        let mut transparent = None;
+        let mut indices = Vec::with_capacity(w * h);
+        for row in data.data.iter() {
+            for &px in row.iter() {
+                let index = px.and_then(|rgb| colours.iter().position(|&c| c == rgb));
+                match index {
+                    Some(i) => { indices.push(i as u8); }
+                    None => {
+                        if transparent.is_none() {
+                            if n == 256 {
+                                return Err(IndexedError::NoTransparentIndex);
+                            }
+                            palette.push(RGB255::new(0, 0, 0));
+                            transparent = Some(n as u8);
+                        }
+                        indices.push(n as u8);
+                    }
+                }
+            }
+        }
+        Ok(Self { w, h, palette, transparent, keep_transparency, indices })
+    }
+    fn palette_bytes(&self) -> Vec<u8> {
+        self.palette.iter().flat_map(|c| vec![c.r, c.g, c.b]).collect()
+    }
+    fn bit_depth(&self) -> png::BitDepth {
+        match self.palette.len() {
+            0..=2 => { png::BitDepth::One }
+            3..=4 => { png::BitDepth::Two }
+            5..=16 => { png::BitDepth::Four }
+            _ => { png::BitDepth::Eight }
+        }
+    }
+    /// Packs indices into rows of the given bit depth.
+    fn packed_rows(&self, depth: png::BitDepth) -> Vec<u8> {
+        let bits = depth as usize;
+        let per_byte = 8 / bits;
+        let stride = self.w.div_ceil(per_byte);
+        let mut data = vec![0u8; stride * self.h];
+        for y in 0..self.h {
+            for x in 0..self.w {
+                let v = self.indices[y * self.w + x];
+                let shift = 8 - bits * (x % per_byte + 1);
+                data[y * stride + x / per_byte] |= v << shift;
+            }
+        }
+        return data;
+    }
+    /// Maps every frame like `from_plot`, sharing one palette between them.
+    pub fn from_frames(frames: &Vec<PlotData<RGB255>>, colours: &Vec<RGB255>,
+                       keep_transparency: bool) -> Result<Vec<Self>, IndexedError> {
+        let mut images = frames.iter()
+            .map(|frame| Self::from_plot(frame, colours, keep_transparency))
+            .collect::<Result<Vec<Self>, IndexedError>>()?;
+        if let Some(extended) = images.iter().find(|image| image.transparent.is_some()) {
+            let (palette, transparent) = (extended.palette.clone(), extended.transparent);
+            for image in images.iter_mut() {
+                image.palette = palette.clone();
+                image.transparent = transparent;
+            }
+        }
+        return Ok(images);
+    }
+    /// Encodes an indexed PNG with the palette in PLTE and transparency in tRNS.
+    pub fn encode_png(&self) -> Result<Vec<u8>, IndexedError> {
+        let mut data = vec![];
+        self.write_png(&mut data)?;
+        return Ok(data);
+    }
+    pub fn save_png(&self, filename: &str) -> Result<(), IndexedError> {
+        let file = std::fs::File::create(filename).map_err(|e| IndexedError::FileWrite(e))?;
+        self.write_png(BufWriter::new(file))
+    }
+    fn write_png<W: Write>(&self, w: W) -> Result<(), IndexedError> {
+        let mut encoder = png::Encoder::new(w, self.w as u32, self.h as u32);
+        let depth = self.bit_depth();
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(depth);
+        encoder.set_palette(self.palette_bytes());
+        if let (Some(t), true) = (self.transparent, self.keep_transparency) {
+            // Entries after the last tRNS one are opaque.
+            let mut trns = vec![0xff; t as usize + 1];
+            trns[t as usize] = 0;
+            encoder.set_trns(trns);
+        }
+        let mut writer = encoder.write_header().map_err(|e| IndexedError::PngEncoding(e))?;
+        writer.write_image_data(&self.packed_rows(depth))
+            .map_err(|e| IndexedError::PngEncoding(e))
+    }
+    pub fn gif_frame(&self) -> gif::Frame<'_> {
+        gif::Frame {
+            width: self.w as u16,
+            height: self.h as u16,
+            buffer: Cow::Borrowed(&self.indices),
+            transparent: if self.keep_transparency { self.transparent } else { None },
+            ..gif::Frame::default()
+        }
+    }
+    pub fn save_gif(&self, filename: &str) -> Result<(), IndexedError> {
+        let file = std::fs::File::create(filename).map_err(|e| IndexedError::FileWrite(e))?;
+        let mut encoder = gif::Encoder::new(
+            BufWriter::new(file), self.w as u16, self.h as u16, &self.palette_bytes()
+        ).map_err(|e| IndexedError::GifEncoding(e))?;
+        encoder.write_frame(&self.gif_frame()).map_err(|e| IndexedError::GifEncoding(e))
+    }
+}
+
+/// Writes a looping animated GIF; `delays` are in milliseconds.
+pub fn save_animated_gif(frames: &Vec<IndexedImage>, delays: &Vec<u32>, filename: &str)
+            -> Result<(), IndexedError> {
+    let first = &frames[0];
+    let file = std::fs::File::create(filename).map_err(|e| IndexedError::FileWrite(e))?;
+    let mut encoder = gif::Encoder::new(
+        BufWriter::new(file), first.w as u16, first.h as u16, &first.palette_bytes()
+    ).map_err(|e| IndexedError::GifEncoding(e))?;
+    encoder.set_repeat(gif::Repeat::Infinite).map_err(|e| IndexedError::GifEncoding(e))?;
+    for (image, &delay) in frames.iter().zip(delays.iter()) {
+        let mut frame = image.gif_frame();
+        frame.delay = ((delay + 5) / 10).min(u16::MAX as u32) as u16;
+        // Frames are full images, so transparent pixels must not show the previous one.
+        frame.dispose = if frame.transparent.is_some() {
+            gif::DisposalMethod::Background
+        } else {
+            gif::DisposalMethod::Keep
+        };
+        encoder.write_frame(&frame).map_err(|e| IndexedError::GifEncoding(e))?;
+    }
+    return Ok(());
+}
+
+/// Writes a looping APNG; `delays` are in milliseconds.
+///
+/// Every frame is encoded as an indexed PNG, and its image data is moved
+/// into the animation chunks of the first one.
+pub fn save_apng(frames: &Vec<IndexedImage>, delays: &Vec<u32>, filename: &str)
+            -> Result<(), IndexedError> {
+    fn frame_control(sequence: u32, image: &IndexedImage, delay: u32) -> PngChunk {
+        let mut data = vec![];
+        data.extend_from_slice(&sequence.to_be_bytes());
+        data.extend_from_slice(&(image.w as u32).to_be_bytes());
+        data.extend_from_slice(&(image.h as u32).to_be_bytes());
+        data.extend_from_slice(&0u32.to_be_bytes());
+        data.extend_from_slice(&0u32.to_be_bytes());
+        data.extend_from_slice(&(delay.min(u16::MAX as u32) as u16).to_be_bytes());
+        data.extend_from_slice(&1000u16.to_be_bytes());
+        // Background disposal, source blending: frames are full images
+        data.push(1);
+        data.push(0);
+        PngChunk::new(*b"fcTL", Bytes::from(data))
+    }
+    let parse = |image: &IndexedImage| -> Result<Png, IndexedError> {
+        Png::from_bytes(Bytes::from(image.encode_png()?)).map_err(|e| IndexedError::PngChunks(e))
+    };
+
+    let mut png = parse(&frames[0])?;
+    png.remove_chunks_by_type(*b"IEND");
+    let mut animation_control = vec![];
+    animation_control.extend_from_slice(&(frames.len() as u32).to_be_bytes());
+    animation_control.extend_from_slice(&0u32.to_be_bytes());
+    let chunks = png.chunks_mut();
+    chunks.insert(1, PngChunk::new(*b"acTL", Bytes::from(animation_control)));
+    let idat = chunks.iter().position(|c| &c.kind() == b"IDAT").unwrap_or(chunks.len());
+    chunks.insert(idat, frame_control(0, &frames[0], delays[0]));
+    let mut sequence = 1;
+    for (image, &delay) in frames.iter().zip(delays.iter()).skip(1) {
+        let frame = parse(image)?;
+        chunks.push(frame_control(sequence, image, delay));
+        sequence += 1;
+        for chunk in frame.chunks_by_type(*b"IDAT") {
+            let mut data = sequence.to_be_bytes().to_vec();
+            data.extend_from_slice(chunk.contents());
+            chunks.push(PngChunk::new(*b"fdAT", Bytes::from(data)));
+            sequence += 1;
+        }
+    }
+    chunks.push(PngChunk::new(*b"IEND", Bytes::new()));
+
+    let file = std::fs::File::create(filename).map_err(|e| IndexedError::FileWrite(e))?;
+    png.encoder().write_to(BufWriter::new(file)).map_err(|e| IndexedError::FileWrite(e))?;
+    return Ok(());
+}
@@ -1,11 +1,12 @@
-#[cfg(not(target_arch = "wasm32"))]
-use ureq;
 use img_parts::{png::Png, jpeg::Jpeg, ImageICC};
 
 #[cfg(not(target_arch = "wasm32"))]
 use image::io::Reader as ImageReader;
 
 use crate::colour::*;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::cache::cache_dir;
+use crate::dither::ThresholdMatrix;
 use crate::metadata;
 
 use std::collections::HashSet;
@@ -54,13 +55,23 @@ pub enum LoadError {
 #[cfg(not(target_arch = "wasm32"))]
     FileRead(std::io::Error),
 #[cfg(not(target_arch = "wasm32"))]
-    NetworkError(ureq::Error),
+    NetworkError(Box<ureq::Error>),
 #[cfg(not(target_arch = "wasm32"))]
     InvalidEncoding(std::io::Error),
 #[cfg(not(target_arch = "wasm32"))]
     ImageEncoding(image::ImageError),
 #[cfg(not(target_arch = "wasm32"))]
-    NotFound
+    NotFound,
+#[cfg(not(target_arch = "wasm32"))]
+    NotCached(String),
+#[cfg(not(target_arch = "wasm32"))]
+    InvalidLospecFile,
+#[cfg(not(target_arch = "wasm32"))]
+    NoColourTable,
+#[cfg(not(target_arch = "wasm32"))]
+    InvalidColourTable,
+#[cfg(not(target_arch = "wasm32"))]
+    FrameSizeMismatch
 }
 impl std::fmt::Display for LoadError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -73,6 +84,13 @@ impl std::fmt::Display for LoadError {
             Self::InvalidEncoding(ref e) => { e.fmt(f) }
             Self::ImageEncoding(ref e) => { e.fmt(f) }
             Self::NotFound => { write!(f, "Palette not found") }
+            Self::NotCached(ref slug) => {
+                write!(f, "Palette `{}` is not cached, it must be downloaded without --offline first", slug)
+            }
+            Self::InvalidLospecFile => { write!(f, "Malformed Lospec palette file") }
+            Self::NoColourTable => { write!(f, "Image has no colour table") }
+            Self::InvalidColourTable => { write!(f, "Malformed colour table") }
+            Self::FrameSizeMismatch => { write!(f, "All frames must have the same size") }
         }
     }
 }
@@ -93,7 +111,193 @@ impl LoadedImage {
     }
 }
 
-pub fn load_image(filename: String) -> Result<LoadedImage, LoadError> {
+pub struct LoadedAnimation {
+    pub frames: Vec<Vec<Vec<Option<RGB255>>>>,
+    /// Frame durations in milliseconds.
+    pub delays: Vec<u32>,
+    pub icc_profile: Option<img_parts::Bytes>
+}
+
+/// Decides what happens to pixels that are not fully opaque.
+///
+/// Pixels with alpha below the threshold are transparent. The rest are
+/// composited in linear light against the background if it is set,
+/// otherwise their alpha is dropped.
+#[derive(Clone, Copy, Debug)]
+pub struct AlphaOptions {
+    pub threshold: u8,
+    pub background: Option<RGB255>
+}
+impl Default for AlphaOptions {
+    fn default() -> Self {
+        Self { threshold: 0xff, background: None }
+    }
+}
+impl AlphaOptions {
+    pub fn apply(&self, rgba: [u8; 4]) -> Option<RGB255> {
+        let [r, g, b, a] = rgba;
+        if a < self.threshold {
+            return None;
+        }
+        let c = RGB255::new(r, g, b);
+        let bg = match self.background {
+            Some(bg) if a < 0xff => { bg }
+            _ => { return Some(c); }
+        };
+        let t = a as f32 / 255.;
+        let blend = |x: u8, y: u8| {
+            let x = ungamma(x as f32 / 255.);
+            let y = ungamma(y as f32 / 255.);
+            gamma(x * t + y * (1. - t))
+        };
+        let rgb = RGB1::new(blend(c.r, bg.r), blend(c.g, bg.g), blend(c.b, bg.b));
+        return Some(rgb.into());
+    }
+}
+
+/// Splits a numbered frame sequence pattern like `frame%03d.png`
+/// into the prefix, the number width and the suffix. Existing files
+/// (like `50%dark.png`) are never patterns.
+fn sequence_pattern(filename: &str) -> Option<(String, usize, String)> {
+    if std::path::Path::new(filename).exists() {
+        return None;
+    }
+    let start = filename.find('%')?;
+    let rest = &filename[start + 1..];
+    let end = rest.find('d')?;
+    let spec = &rest[..end];
+    if !spec.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let width = if spec.is_empty() { 0 } else { str::parse(spec).ok()? };
+    return Some((filename[..start].into(), width, rest[end + 1..].into()));
+}
+
+/// Whether the input is an animated GIF or APNG, or a numbered frame sequence.
+pub fn is_animation(filename: &str) -> bool {
+    use image::AnimationDecoder;
+    use image::codecs::{gif::GifDecoder, png::PngDecoder};
+    if sequence_pattern(filename).is_some() {
+        return true;
+    }
+    let lower = filename.to_lowercase();
+    let file = match std::fs::File::open(filename) {
+        Ok(x) => { std::io::BufReader::new(x) }
+        Err(_) => { return false; }
+    };
+    if lower.ends_with(".gif") {
+        return match GifDecoder::new(file) {
+            Ok(decoder) => { decoder.into_frames().take(2).count() > 1 }
+            Err(_) => { false }
+        };
+    }
+    if lower.ends_with(".png") {
+        return match PngDecoder::new(file) {
+            Ok(decoder) => { decoder.is_apng() }
+            Err(_) => { false }
+        };
+    }
+    return false;
+}
+
+/// Loads all frames of an animated GIF or APNG, or of a numbered frame sequence
+/// (which gets `sequence_delay` ms per frame). Frames are full composited images.
+pub fn load_animation(filename: &str, alpha: AlphaOptions, sequence_delay: u32)
+            -> Result<LoadedAnimation, LoadError> {
+    use image::{AnimationDecoder, ImageDecoder};
+    use image::codecs::{gif::GifDecoder, png::PngDecoder};
+
+    if let Some((prefix, width, suffix)) = sequence_pattern(filename) {
+        let name = |i: usize| format!("{}{:0width$}{}", prefix, i, suffix, width = width);
+        let first = if std::path::Path::new(&name(0)).exists() { 0 } else { 1 };
+        let mut frames = vec![];
+        let mut icc_profile = None;
+        let mut i = first;
+        while std::path::Path::new(&name(i)).exists() {
+            let image = load_image(name(i), alpha)?;
+            if let Some(first) = frames.first() {
+                if !same_size(first, &image.data) {
+                    return Err(LoadError::FrameSizeMismatch);
+                }
+            } else {
+                icc_profile = image.icc_profile;
+            }
+            frames.push(image.data);
+            i += 1;
+        }
+        if frames.is_empty() {
+            return Err(LoadError::FileOpen(
+                std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} not found", name(first)))
+            ));
+        }
+        let delays = vec![sequence_delay; frames.len()];
+        return Ok(LoadedAnimation { frames, delays, icc_profile });
+    }
+
+    let file = std::io::BufReader::new(
+        std::fs::File::open(filename).map_err(|e| LoadError::FileOpen(e))?
+    );
+    let (w, h, decoded) = if filename.to_lowercase().ends_with(".gif") {
+        let decoder = GifDecoder::new(file).map_err(|e| LoadError::ImageEncoding(e))?;
+        let (w, h) = decoder.dimensions();
+        (w, h, decoder.into_frames().collect_frames())
+    } else {
+        let decoder = PngDecoder::new(file).map_err(|e| LoadError::ImageEncoding(e))?;
+        let (w, h) = decoder.dimensions();
+        (w, h, decoder.apng().into_frames().collect_frames())
+    };
+    let decoded = decoded.map_err(|e| LoadError::ImageEncoding(e))?;
+    let mut frames = vec![];
+    let mut delays = vec![];
+    for frame in decoded.iter() {
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        delays.push((numer + denom / 2).checked_div(denom).unwrap_or(0));
+        let mut data = vec![vec![None; w as usize]; h as usize];
+        let buffer = frame.buffer();
+        for y in 0..buffer.height() {
+            for x in 0..buffer.width() {
+                let (xx, yy) = (x + frame.left(), y + frame.top());
+                if xx < w && yy < h {
+                    data[yy as usize][xx as usize] = alpha.apply(buffer.get_pixel(x, y).0);
+                }
+            }
+        }
+        frames.push(data);
+    }
+
+    let mut icc_profile = None;
+    if filename.ends_with(".png") {
+        if let Ok(data) = std::fs::read(filename) {
+            if let Ok(png) = Png::from_bytes(data.into()) {
+                icc_profile = png.icc_profile();
+            }
+        }
+    }
+    return Ok(LoadedAnimation { frames, delays, icc_profile });
+}
+
+fn same_size<T>(a: &Vec<Vec<T>>, b: &Vec<Vec<T>>) -> bool {
+    a.len() == b.len() && a.first().map(|r| r.len()) == b.first().map(|r| r.len())
+}
+
+/// Reads a greyscale image as a threshold map: darker pixels get lower thresholds.
+pub fn load_threshold_map(filename: &str) -> Result<ThresholdMatrix, LoadError> {
+    let image = ImageReader::open(filename)
+        .map_err(|e| LoadError::FileOpen(e))?
+        .decode().map_err(|e| LoadError::ImageEncoding(e))?
+        .to_luma8();
+    let w = image.width() as usize;
+    let h = image.height() as usize;
+    let mut scores = vec![vec![0.; w]; h];
+    for y in 0..h {
+        for x in 0..w {
+            scores[y][x] = image.get_pixel(x as u32, y as u32).0[0] as f32;
+        }
+    }
+    return Ok(ThresholdMatrix::from_scores(w, h, &scores));
+}
+
+pub fn load_image(filename: String, alpha: AlphaOptions) -> Result<LoadedImage, LoadError> {
     let image = ImageReader::open(&filename)
         .map_err(|e| LoadError::FileOpen(e))?
         .decode().map_err(|e| LoadError::ImageEncoding(e))?
@@ -104,11 +308,7 @@ pub fn load_image(filename: String) -> Result<LoadedImage, LoadError> {
     for y in 0..h {
         for x in 0..w {
             let c = image.get_pixel(x, y);
-            let [r, g, b, a] = c.0;
-            if a == 0xff {
-                let c = RGB255::new(r, g, b);
-                data[y as usize][x as usize] = Some(c);
-            }
+            data[y as usize][x as usize] = alpha.apply(c.0);
         }
     }
 
@@ -153,7 +353,7 @@ impl LoadedPalette {
 }
 
 #[cfg(not(target_arch = "wasm32"))]
-pub fn load_from_image(filename: String) -> Result<LoadedPalette, LoadError> {
+pub fn load_from_image(filename: String, alpha: AlphaOptions) -> Result<LoadedPalette, LoadError> {
     let image = ImageReader::open(&filename)
         .map_err(|e| LoadError::FileOpen(e))?
         .decode().map_err(|e| LoadError::ImageEncoding(e))?
@@ -164,9 +364,7 @@ pub fn load_from_image(filename: String) -> Result<LoadedPalette, LoadError> {
     for y in 0..h {
         for x in 0..w {
             let c = image.get_pixel(x, y);
-            let [r, g, b, a] = c.0;
-            if a == 0xff {
-                let c = RGB255::new(r, g, b);
+            if let Some(c) = alpha.apply(c.0) {
                 if !colours.contains(&c) {
                     colours.push(c);
                 }
@@ -198,17 +396,223 @@ pub fn load_from_image(filename: String) -> Result<LoadedPalette, LoadError> {
     return Ok(palette);
 }
 
+/// Reads the colour table of an indexed PNG, GIF or BMP image as is,
+/// in index order and including the entries no pixel uses.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn load_from_colour_table(filename: String) -> Result<LoadedPalette, LoadError> {
+    let data = std::fs::read(&filename).map_err(|e| LoadError::FileOpen(e))?;
+    if data.starts_with(b"\x89PNG") {
+        let png = Png::from_bytes(data.into()).map_err(|_| LoadError::InvalidColourTable)?;
+        let plte = png.chunk_by_type(*b"PLTE").ok_or(LoadError::NoColourTable)?;
+        let colours = parse_colour_table(plte.contents(), 3)?;
+        let mut palette = LoadedPalette::new(colours);
+        if let Some(profile) = png.icc_profile() {
+            palette = palette.with_icc_profile(profile);
+        }
+        return Ok(palette);
+    }
+    if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        let colours = gif_colour_table(&data)?;
+        return Ok(LoadedPalette::new(colours));
+    }
+    if data.starts_with(b"BM") {
+        let colours = bmp_colour_table(&data)?;
+        return Ok(LoadedPalette::new(colours));
+    }
+    return Err(LoadError::NoColourTable);
+}
+
+/// Entries are RGB triples (PNG, GIF) or BGR with an optional padding byte (BMP).
+#[cfg(not(target_arch = "wasm32"))]
+fn parse_colour_table(data: &[u8], entry: usize) -> Result<Vec<RGB255>, LoadError> {
+    let entries = data.chunks_exact(entry);
+    if !entries.remainder().is_empty() {
+        return Err(LoadError::InvalidColourTable);
+    }
+    let colours = entries
+        .map(|c| RGB255::new(c[0], c[1], c[2]))
+        .collect();
+    return Ok(colours);
+}
+
+/// Where a colour table lies in an image file.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ColourTableSpan {
+    pub offset: usize,
+    pub count: usize,
+    /// Bytes per entry.
+    pub entry: usize,
+    /// Whether entries are stored as BGR rather than RGB.
+    pub bgr: bool
+}
+#[cfg(not(target_arch = "wasm32"))]
+impl ColourTableSpan {
+    pub fn range(&self) -> std::ops::Range<usize> {
+        self.offset..self.offset + self.count * self.entry
+    }
+    pub fn read(&self, data: &[u8]) -> Result<Vec<RGB255>, LoadError> {
+        let table = data.get(self.range()).ok_or(LoadError::InvalidColourTable)?;
+        let colours = parse_colour_table(table, self.entry)?;
+        if self.bgr {
+            return Ok(colours.into_iter().map(|c| RGB255::new(c.b, c.g, c.r)).collect());
+        }
+        return Ok(colours);
+    }
+}
+
+/// Returns the global colour table, or the local one of the first frame that has one.
+#[cfg(not(target_arch = "wasm32"))]
+fn gif_colour_table(data: &[u8]) -> Result<Vec<RGB255>, LoadError> {
+    let tables = gif_colour_tables(data)?;
+    let table = tables.first().ok_or(LoadError::NoColourTable)?;
+    return table.read(data);
+}
+
+/// Finds the global colour table and the local ones of all frames, in file order.
 #[cfg(not(target_arch = "wasm32"))]
-pub fn load_from_lospec(slug: String) -> Result<LoadedPalette, LoadError> {
-    let url = format!("https://lospec.com/palette-list/{}.csv", slug);
+pub fn gif_colour_tables(data: &[u8]) -> Result<Vec<ColourTableSpan>, LoadError> {
+    fn table(flags: u8, offset: usize) -> ColourTableSpan {
+        ColourTableSpan { offset, count: 1 << ((flags & 0x07) + 1), entry: 3, bgr: false }
+    }
+    fn skip_sub_blocks(data: &[u8], mut i: usize) -> Result<usize, LoadError> {
+        loop {
+            let n = *data.get(i).ok_or(LoadError::InvalidColourTable)? as usize;
+            i += 1 + n;
+            if n == 0 { return Ok(i); }
+        }
+    }
+    let mut tables = vec![];
+    let flags = *data.get(10).ok_or(LoadError::InvalidColourTable)?;
+    let mut i = 13;
+    if flags & 0x80 != 0 {
+        tables.push(table(flags, i));
+        i = tables[0].range().end;
+    }
+    loop {
+        match data.get(i) {
+            Some(0x21) => {
+                // Extension: label, then data sub-blocks
+                i = skip_sub_blocks(data, i + 2)?;
+            }
+            Some(0x2c) => {
+                let flags = *data.get(i + 9).ok_or(LoadError::InvalidColourTable)?;
+                i += 10;
+                if flags & 0x80 != 0 {
+                    let local = table(flags, i);
+                    i = local.range().end;
+                    tables.push(local);
+                }
+                // LZW minimum code size, then image data sub-blocks
+                i = skip_sub_blocks(data, i + 1)?;
+            }
+            Some(0x3b) => { return Ok(tables); }
+            _ => { return Err(LoadError::InvalidColourTable); }
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn bmp_colour_table(data: &[u8]) -> Result<Vec<RGB255>, LoadError> {
+    let (table, _) = bmp_colour_table_span(data)?;
+    return table.read(data);
+}
+
+/// Finds the colour table of a BMP file; also returns the bits per pixel, since
+/// images with more than 8 may carry a table without being indexed.
+pub fn bmp_colour_table_span(data: &[u8]) -> Result<(ColourTableSpan, usize), LoadError> {
+    let field = |i: usize, n: usize| -> Result<usize, LoadError> {
+        let b = data.get(i..i + n).ok_or(LoadError::InvalidColourTable)?;
+        Ok(b.iter().rev().fold(0, |x, &b| (x << 8) | b as usize))
+    };
+    let header_size = field(14, 4)?;
+    let (bpp, used, entry) = if header_size == 12 {
+        // OS/2 BITMAPCOREHEADER
+        (field(24, 2)?, 0, 3)
+    } else {
+        (field(28, 2)?, field(46, 4)?, 4)
+    };
+    let count = match used {
+        0 if bpp <= 8 => { 1 << bpp }
+        0 => { return Err(LoadError::NoColourTable); }
+        _ => { used }
+    };
+    let table = ColourTableSpan { offset: 14 + header_size, count, entry, bgr: true };
+    return Ok((table, bpp));
+}
+
+pub const LOSPEC_URL: &str = "https://lospec.com/palette-list";
+
+/// Loads a palette from Lospec or from a mirror at `base_url`.
+///
+/// Downloaded palettes are kept in the app cache directory, separately for
+/// every mirror, and reused; in offline mode only the cached ones are available.
+/// Unreadable cached palettes are downloaded again.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn load_from_lospec(slug: String, base_url: &str, offline: bool)
+            -> Result<LoadedPalette, LoadError> {
+    let valid = !slug.is_empty() && slug.chars().all(
+        |c| c.is_ascii_alphanumeric() || c == '-' || c == '_'
+    );
+    if !valid {
+        return Err(LoadError::NotFound);
+    }
+    let base_url = base_url.trim_end_matches('/');
+    let cache_file = cache_dir()
+        .map(|dir| dir.join(lospec_cache_name(base_url)).join(format!("{}.csv", slug)));
+    if let Ok(ref cache_file) = cache_file {
+        if let Ok(csv) = std::fs::read_to_string(cache_file) {
+            match parse_lospec_csv(&csv) {
+                Ok(palette) => { return Ok(palette); }
+                Err(e) if offline => { return Err(e); }
+                Err(_) => {}
+            }
+        }
+    }
+    if offline {
+        return Err(LoadError::NotCached(slug));
+    }
+    let url = format!("{}/{}.csv", base_url, slug);
     let csv = ureq::get(&url)
         .set("User-Agent", &format!("censor v{}", metadata::VERSION))
-        .call().map_err(|e| LoadError::NetworkError(e))?
+        .call().map_err(|e| LoadError::NetworkError(Box::new(e)))?
         .into_string().map_err(|e| LoadError::InvalidEncoding(e))?;
     if csv == "file not found" {
         return Err(LoadError::NotFound);
     }
-    let colours = csv.split(',')
+    let palette = parse_lospec_csv(&csv)?;
+    // Caching is best-effort.
+    if let Ok(cache_file) = cache_file {
+        if let Some(dir) = cache_file.parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+        let _ = std::fs::write(cache_file, &csv);
+    }
+    Ok(palette)
+}
+
+/// Cache subdirectory of the palettes downloaded from `base_url`:
+/// `lospec` for Lospec itself, `lospec-HASH` for mirrors.
+#[cfg(not(target_arch = "wasm32"))]
+fn lospec_cache_name(base_url: &str) -> String {
+    if base_url == LOSPEC_URL {
+        return "lospec".into();
+    }
+    // FNV-1a, which unlike the std hashers is stable across builds.
+    let hash = base_url.bytes().fold(0xcbf29ce484222325u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    return format!("lospec-{:016x}", hash);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn parse_lospec_csv(csv: &str) -> Result<LoadedPalette, LoadError> {
+    // Name, author and at least one colour.
+    let fields: Vec<&str> = csv.trim_end().split(',').collect();
+    if fields.len() < 3 {
+        return Err(LoadError::InvalidLospecFile);
+    }
+    let colours = fields.into_iter()
         .skip(2)
         .map(|s| parse_hex(s.into()))
         .collect::<Result<Vec<_>, _>>()?;
@@ -235,7 +639,7 @@ pub fn load_from_hex(data: &Vec<String>) -> Result<LoadedPalette, LoadError> {
     Ok(LoadedPalette::new(colours))
 }
 
-fn parse_hex(x: String) -> Result<RGB255, LoadError> {
+pub fn parse_hex(x: String) -> Result<RGB255, LoadError> {
     if x.len() < 6 || x.len() > 7 || (x.len() == 7 && !x.starts_with('#')) {
         return Err(LoadError::InvalidHexLength);
     }
@@ -248,3 +652,189 @@ fn parse_hex(x: String) -> Result<RGB255, LoadError> {
     let b = u8::from_str_radix(&b, 16).map_err(|_| LoadError::NonHexCharacters)?;
     return Ok(RGB255::new(r, g, b));
 }
+
+/// A connected single-coloured area of an image.
+struct Region {
+    colour: RGB255,
+    x_min: u32,
+    x_max: u32,
+    y_min: u32,
+    y_max: u32,
+    count: u32
+}
+impl Region {
+    fn area(&self) -> u32 {
+        (self.x_max - self.x_min + 1) * (self.y_max - self.y_min + 1)
+    }
+    fn fill(&self) -> f32 {
+        self.count as f32 / self.area() as f32
+    }
+}
+
+/// Finds the 4-connected single-coloured regions of opaque pixels.
+fn find_regions(data: &Vec<Vec<Option<RGB255>>>) -> Vec<Region> {
+    let h = data.len();
+    let w = if h > 0 { data[0].len() } else { 0 };
+    let mut visited = vec![vec![false; w]; h];
+    let mut regions = vec![];
+    for y0 in 0..h {
+        for x0 in 0..w {
+            if visited[y0][x0] { continue; }
+            let colour = match data[y0][x0] {
+                Some(c) => { c }
+                None => { continue; }
+            };
+            let mut region = Region {
+                colour,
+                x_min: x0 as u32, x_max: x0 as u32,
+                y_min: y0 as u32, y_max: y0 as u32,
+                count: 0
+            };
+            let mut stack = vec![(x0, y0)];
+            visited[y0][x0] = true;
+            while let Some((x, y)) = stack.pop() {
+                region.count += 1;
+                region.x_min = u32::min(region.x_min, x as u32);
+                region.x_max = u32::max(region.x_max, x as u32);
+                region.y_min = u32::min(region.y_min, y as u32);
+                region.y_max = u32::max(region.y_max, y as u32);
+                let mut neighbours = vec![];
+                if x > 0 { neighbours.push((x - 1, y)); }
+                if y > 0 { neighbours.push((x, y - 1)); }
+                if x + 1 < w { neighbours.push((x + 1, y)); }
+                if y + 1 < h { neighbours.push((x, y + 1)); }
+                for (xx, yy) in neighbours {
+                    if !visited[yy][xx] && data[yy][xx] == Some(colour) {
+                        visited[yy][xx] = true;
+                        stack.push((xx, yy));
+                    }
+                }
+            }
+            regions.push(region);
+        }
+    }
+    return regions;
+}
+
+/// Picks the colours of uniform rectangular cells in reading order.
+///
+/// Regions that are far from rectangular (backgrounds with holes, borders,
+/// grid lines) are ignored, and so are the ones much bigger or smaller
+/// than a typical cell (backgrounds, label glyphs). The typical cell area is
+/// the median over pixels rather than regions, so that numerous small label
+/// glyphs don't outvote the cells.
+pub fn detect_swatches(data: &Vec<Vec<Option<RGB255>>>) -> Vec<RGB255> {
+    let candidates: Vec<Region> = find_regions(data).into_iter()
+        .filter(|r| r.fill() >= 0.75)
+        .collect();
+    if candidates.is_empty() {
+        return vec![];
+    }
+    let mut areas: Vec<(u32, u32)> = candidates.iter()
+        .filter(|r| r.x_max > r.x_min && r.y_max > r.y_min)
+        .map(|r| (r.area(), r.count))
+        .collect();
+    if areas.is_empty() {
+        areas = candidates.iter().map(|r| (r.area(), r.count)).collect();
+    }
+    areas.sort();
+    let total: u32 = areas.iter().map(|&(_, count)| count).sum();
+    let mut seen = 0;
+    let typical = areas.iter()
+        .find(|&&(_, count)| {
+            seen += count;
+            2 * seen >= total
+        })
+        .unwrap().0;
+    let mut cells: Vec<Region> = candidates.into_iter()
+        .filter(|r| r.area() * 4 >= typical && r.area() <= typical * 4)
+        .collect();
+
+    // Group the cells into rows by their vertical extent, then read the rows.
+    cells.sort_by_key(|r| (r.y_min + r.y_max, r.x_min));
+    let mut rows: Vec<Vec<Region>> = vec![];
+    for cell in cells {
+        let centre = (cell.y_min + cell.y_max) / 2;
+        match rows.last_mut() {
+            Some(row) if row[0].y_min <= centre && centre <= row[0].y_max => {
+                row.push(cell);
+            }
+            _ => {
+                rows.push(vec![cell]);
+            }
+        }
+    }
+    let mut colours = vec![];
+    for mut row in rows {
+        row.sort_by_key(|r| r.x_min);
+        for cell in row {
+            if !colours.contains(&cell.colour) {
+                colours.push(cell.colour);
+            }
+        }
+    }
+    return colours;
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn load_from_swatches(filename: String, alpha: AlphaOptions) -> Result<LoadedPalette, LoadError> {
+    let image = load_image(filename, alpha)?;
+    let colours = detect_swatches(&image.data);
+    let mut palette = LoadedPalette::new(colours);
+    if let Some(profile) = image.icc_profile {
+        palette = palette.with_icc_profile(profile);
+    }
+    return Ok(palette);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CELL: usize = 10;
+    const BORDER: usize = 2;
+    const COLOURS: [u32; 8] = [
+        0xff0000, 0x00ff00, 0x0000ff, 0xffff00, 0xff00ff, 0x00ffff, 0x808080, 0x804000
+    ];
+
+    fn rgb(hex: u32) -> RGB255 {
+        RGB255::new((hex >> 16) as u8, (hex >> 8) as u8, hex as u8)
+    }
+
+    /// A 4x2 grid of cells with black borders, optionally with a white mark in every cell.
+    fn grid(labelled: bool) -> Vec<Vec<Option<RGB255>>> {
+        let (cols, rows) = (4, 2);
+        let w = cols * (CELL + BORDER) + BORDER;
+        let h = rows * (CELL + BORDER) + BORDER;
+        let mut data = vec![vec![Some(rgb(0x000000)); w]; h];
+        for (k, &hex) in COLOURS.iter().enumerate() {
+            let x0 = BORDER + (k % cols) * (CELL + BORDER);
+            let y0 = BORDER + (k / cols) * (CELL + BORDER);
+            for y in y0..y0 + CELL {
+                for x in x0..x0 + CELL {
+                    data[y][x] = Some(rgb(hex));
+                }
+            }
+            if labelled {
+                for y in y0 + 2..y0 + 5 {
+                    for x in x0 + 2..x0 + 4 {
+                        data[y][x] = Some(rgb(0xffffff));
+                    }
+                }
+            }
+        }
+        return data;
+    }
+
+    #[test]
+    fn bordered_grid_swatches() {
+        let expected: Vec<RGB255> = COLOURS.iter().map(|&c| rgb(c)).collect();
+        assert_eq!(detect_swatches(&grid(false)), expected);
+    }
+
+    #[test]
+    fn labelled_grid_swatches() {
+        let expected: Vec<RGB255> = COLOURS.iter().map(|&c| rgb(c)).collect();
+        assert_eq!(detect_swatches(&grid(true)), expected);
+    }
+}
@@ -1,4 +1,15 @@
 #![allow(non_snake_case)]
+// Lints against the style of the whole codebase: explicit returns, grids passed
+// as `&Vec<Vec<_>>` and indexed by coordinates, `map_err(|e| Error::Variant(e))`
+// and colour spaces named by their acronyms (CIEXYZ, RGB255).
+// Everything else is allowed only where it occurs.
+#![allow(
+    clippy::needless_return,
+    clippy::ptr_arg,
+    clippy::needless_range_loop,
+    clippy::redundant_closure,
+    clippy::upper_case_acronyms
+)]
 
 mod util;
 mod colour;
@@ -11,16 +22,28 @@ mod analyse;
 mod loader;
 #[cfg(not(target_arch = "wasm32"))]
 mod daemon;
+#[cfg(not(target_arch = "wasm32"))]
+mod commands;
 mod web;
 mod metadata;
 mod dither;
+mod constrained;
+mod resize;
+mod subset;
+mod usage;
+#[cfg(not(target_arch = "wasm32"))]
+mod remap;
+mod coverage;
+mod valuecheck;
+mod shading;
+#[cfg(not(target_arch = "wasm32"))]
+mod exporter;
+#[cfg(not(target_arch = "wasm32"))]
+mod indexed;
 
 #[cfg(target_arch = "wasm32")]
 use stdweb;
 
-use image::RgbImage;
-use img_parts::{png::Png, ImageICC};
-use text_io::scan;
 
 use crate::colour::*;
 use crate::palette::*;
@@ -28,7 +51,6 @@ use crate::text::Font;
 use crate::cache::*;
 use crate::analyse::*;
 use crate::loader::*;
-use crate::dither::*;
 
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
@@ -99,54 +121,41 @@ fn main() {
         main_dither(matches);
         return;
     }
+    if let Some(matches) = matches.subcommand_matches("convert") {
+        main_convert(matches);
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("usage") {
+        main_usage(matches);
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("remap") {
+        main_remap(matches);
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("coverage") {
+        main_coverage(matches);
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("shade") {
+        main_shade(matches);
+        return;
+    }
     eprintln!("Usage information:");
     eprintln!("\tcensor --help");
     std::process::exit(1);
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 fn palette_from_cmd<'a>(matches: &clap::ArgMatches<'a>, verbose: bool)
             -> LoadedPalette {
-    let list_provided = matches.value_of("colours").is_some();
-    let file_provided = matches.value_of("hexfile").is_some();
-    let slug_provided = matches.value_of("lospec").is_some();
-    let image_provided = matches.value_of("imagefile").is_some();
-
-    let result;
-
-    match (list_provided, file_provided, slug_provided, image_provided) {
-        (true, false, false, false) => {
-            let hex_list = matches.value_of("colours").unwrap();
-            let hex_list = hex_list.split(',')
-                .map(|s| String::from(s))
-                .collect::<Vec<_>>();
-            result = load_from_hex(&hex_list);
-        }
-        (false, true, false, false) => {
-            let filename = matches.value_of("hexfile").unwrap();
-            result = load_from_file(filename.into());
-        }
-        (false, false, true, false) => {
-            let slug = matches.value_of("lospec").unwrap();
-            if verbose { eprintln!("Downloading palette..."); }
-            result = load_from_lospec(slug.into());
-        }
-        (false, false, false, true) => {
-            let filename = matches.value_of("imagefile").unwrap();
-            result = load_from_image(filename.into());
-        }
-        _ => {
-            eprintln!("Impossible happened! Blame the `clap` library. Report this error.");
-            std::process::exit(1);
-        }
-    }
-    let palette = match result {
+    match commands::palette_from_cmd(matches, verbose) {
         Ok(x) => { x }
         Err(e) => {
-            eprintln!("Error while getting palette: {}", e);
+            eprintln!("{}", e);
             std::process::exit(1);
         }
-    };
-    return palette;
+    }
 }
 
 fn main_analyse<'a>(matches: &clap::ArgMatches<'a>) {
@@ -207,15 +216,10 @@ fn main_analyse<'a>(matches: &clap::ArgMatches<'a>) {
                 Arc::new(font), grey_ui, outfile, verbose
             );
         });
-        loop {
-            match cp_req_recv.recv() {
-                Ok(()) => {
-                    let (recv, send) = cache_hoster.register();
-                    let cache_provider = MultithreadedCacheProvider::new(T, ill, send, recv);
-                    cp_send.send(cache_provider).unwrap();
-                }
-                Err(_) => { break; }
-            }
+        while let Ok(()) = cp_req_recv.recv() {
+            let (recv, send) = cache_hoster.register();
+            let cache_provider = MultithreadedCacheProvider::new(T, ill, send, recv);
+            cp_send.send(cache_provider).unwrap();
         }
         cache_hoster.process();
         handle.join().unwrap();
@@ -232,7 +236,7 @@ fn main_daemon<'a>(matches: &clap::ArgMatches<'a>) {
     let verbose = matches.is_present("verbose");
 
     let port_str = matches.value_of("port").unwrap();
-    let port = match u16::from_str_radix(port_str, 10) {
+    let port = match port_str.parse::<u16>() {
         Ok(port) => { port }
         Err(e) => {
             eprintln!("Error parsing daemon port: {}", e);
@@ -288,15 +292,14 @@ fn main_compute<'a>(matches: &clap::ArgMatches<'a>) {
 
     for metric in metrics {
         if enabled[metric] {
-            let v: String;
-            match metric {
+            let v = match metric {
                 "iss" => {
                     let iss = palette.internal_similarity();
-                    v = format!("{:.2}", iss);
+                    format!("{:.2}", iss)
                 }
                 "acyclic" => {
                     let acyclic = palette.is_acyclic();
-                    v = format!("{}", acyclic);
+                    format!("{}", acyclic)
                 }
                 _ => { continue; }
             };
@@ -307,125 +310,57 @@ fn main_compute<'a>(matches: &clap::ArgMatches<'a>) {
 
 fn main_dither<'a>(matches: &clap::ArgMatches<'a>) {
     let verbose = matches.is_present("verbose");
-
-    let T: f32;
-    if let Some(D) = matches.value_of("D") {
-        match D {
-            "50" => { T = 5000.00 }
-            "55" => { T = 5500.00 }
-            "65" => { T = 6503.51 }
-            _ => {
-                eprintln!("Invalid illuminant preset: D{}", D);
-                std::process::exit(1);
-            }
+    let mut cacher = BigCacher::init(verbose);
+    match commands::dither(matches, &mut cacher, verbose) {
+        Ok(report) => { print!("{}", report); }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
-    } else {
-        T = match str::parse(matches.value_of("T").unwrap_or("5500")) {
-            Ok(x) => { x }
-            Err(e) => {
-                eprintln!("Error parsing temperature: {}", e);
-                std::process::exit(1);
-            }
-        };
     }
-    let ill = CAT16Illuminant::new(CIExy::from_T(T));
+}
 
-    let mut outfile: String = matches.value_of("outfile").unwrap_or("plot.png").into();
-    if !outfile.ends_with(".png") {
-        outfile = format!("{}.png", outfile);
+#[cfg(not(target_arch = "wasm32"))]
+fn main_usage<'a>(matches: &clap::ArgMatches<'a>) {
+    let verbose = matches.is_present("verbose");
+    if let Err(e) = commands::usage(matches, verbose) {
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
+}
 
-    let palette = palette_from_cmd(matches, verbose);
-    let palette = Palette::new(palette.colours.clone(), &ill, false);
-
-    let image_filename = matches.value_of("imageinput").unwrap();
-    let image = match load_image(image_filename.into()) {
-        Ok(x) => { x }
-        Err(e) => {
-            eprintln!("Error loading input image: {}", e);
-            std::process::exit(1);
-        }
-    };
-    let h = image.data.len() as u32;
-    let w = image.data[0].len() as u32;
-
-    if verbose { eprintln!("Converting the image into CAM16UCS...") }
-    let icc_profile = image.icc_profile;
-    let image_cam16: Vec<Vec<Option<CAM16UCS>>> = image.data.iter().map(
-        |row| row.iter().map(
-            |opt| opt.map(
-                |rgb| CAM16UCS::of(CIEXYZ::from(rgb), &ill)
-            )
-        ).collect()
-    ).collect();
-    let plot = PlotData::new(image_cam16);
-
-    let nodither_provided = matches.is_present("nodither");
-    let bayer_provided = matches.is_present("bayer");
-    let whitenoise_provided = matches.is_present("whitenoise");
-    let bluenoise_provided = matches.is_present("bluenoise");
-
-    let method = match () {
-        () if nodither_provided => { DitheringMethod::None }
-        () if bayer_provided => {
-            let n = match str::parse(matches.value_of("bayer").unwrap()) {
-                Ok(x) => { x }
-                Err(e) => {
-                    eprintln!("Could not parse Bayer matrix size: {}", e);
-                    std::process::exit(1);
-                }
-            };
-            DitheringMethod::Bayer(n)
-        }
-        () if whitenoise_provided => {
-            let wxh = matches.value_of("whitenoise").unwrap();
-            let w: usize;
-            let h: usize;
-            scan!(wxh.bytes() => "{}x{}", w, h);
-            DitheringMethod::WhiteNoise(w, h)
-        }
-        () if bluenoise_provided => {
-            let wxh = matches.value_of("bluenoise").unwrap();
-            let w: usize;
-            let h: usize;
-            scan!(wxh.bytes() => "{}x{}", w, h);
-            DitheringMethod::BlueNoise(w, h)
-        }
-        () => { DitheringMethod::default() }
-    };
-
-    let dithered = Ditherer::dither(plot, &palette, method, verbose);
+#[cfg(not(target_arch = "wasm32"))]
+fn main_remap<'a>(matches: &clap::ArgMatches<'a>) {
+    let verbose = matches.is_present("verbose");
+    if let Err(e) = commands::remap(matches, verbose) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
 
-    let mut image = RgbImage::new(w, h);
-    for y in 0..h {
-        for x in 0..w {
-            match dithered.data[y as usize][x as usize] {
-                Some(rgb) => {
-                    image.put_pixel(x, y, rgb.into());
-                }
-                None => {}
-            }
-        }
+#[cfg(not(target_arch = "wasm32"))]
+fn main_coverage<'a>(matches: &clap::ArgMatches<'a>) {
+    let verbose = matches.is_present("verbose");
+    if let Err(e) = commands::coverage(matches, verbose) {
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
-    if let Err(e) = image.save(&outfile) {
-        eprintln!("Error saving output image: {}", e);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn main_shade<'a>(matches: &clap::ArgMatches<'a>) {
+    let verbose = matches.is_present("verbose");
+    if let Err(e) = commands::shade(matches, verbose) {
+        eprintln!("{}", e);
         std::process::exit(1);
     }
+}
 
-    if let Some(ref icc_profile) = icc_profile {
-        let data = match std::fs::read(&outfile) {
-            Ok(x) => { x }
-            Err(_) => { return; }
-        };
-        let mut png = match Png::from_bytes(data.into()) {
-            Ok(x) => { x }
-            Err(_) => { return; }
-        };
-        png.set_icc_profile(Some(icc_profile.clone()));
-        let file = match std::fs::File::create(&outfile) {
-            Ok(x) => { x }
-            Err(_) => { return; }
-        };
-        let _ = png.encoder().write_to(file);
+#[cfg(not(target_arch = "wasm32"))]
+fn main_convert<'a>(matches: &clap::ArgMatches<'a>) {
+    let verbose = matches.is_present("verbose");
+    if let Err(e) = commands::convert(matches, verbose) {
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
 }
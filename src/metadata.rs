@@ -7,15 +7,23 @@ pub const VERSION_MINOR: &str = env!("CARGO_PKG_VERSION_MINOR");
 pub const VERSION_PATCH: &str = env!("CARGO_PKG_VERSION_PATCH");
 pub const VERSION: &str = formatcp!("{}.{}.{}", VERSION_MAJOR, VERSION_MINOR, VERSION_PATCH);
 pub const REPO: &str = env!("CARGO_PKG_REPOSITORY");
+/// Dithering methods compared by `dither --compare` unless listed.
+pub const COMPARE_DEFAULT: &str = "none,bayer,bluenoise,pattern,floyd-steinberg,atkinson,jarvis,sierra";
 
 pub fn cmd_parser<'a, 'b>() -> App<'a, 'b> {
     let (palette_input_group, palette_input_args) = palette_input_args();
+    let alpha_args = alpha_args();
     let image_input_args = image_input_args();
     let (dither_groups, dither_args) = dither_args();
     let (interp_groups, interp_args) = interpretation_args();
     let (metrics_group, metrics_args) = metrics_args();
     let (repr_groups, repr_args) = representation_args();
     let (comp_groups, comp_args) = computation_args();
+    let (convert_groups, convert_args) = convert_args();
+    let usage_args = usage_args();
+    let (remap_groups, remap_args) = remap_args();
+    let coverage_args = coverage_args();
+    let shade_args = shade_args();
     let verbose = verbose_arg();
 
     let daemon = SubCommand::with_name("daemon")
@@ -35,6 +43,7 @@ pub fn cmd_parser<'a, 'b>() -> App<'a, 'b> {
         .arg(verbose.clone())
         .group(palette_input_group.clone())
         .args(palette_input_args.as_slice())
+        .args(alpha_args.as_slice())
         .groups(interp_groups.as_slice())
         .args(interp_args.as_slice())
         .groups(repr_groups.as_slice())
@@ -53,6 +62,7 @@ pub fn cmd_parser<'a, 'b>() -> App<'a, 'b> {
         .about("Computes palette metrics.")
         .group(palette_input_group.clone())
         .args(palette_input_args.as_slice())
+        .args(alpha_args.as_slice())
         .groups(interp_groups.as_slice())
         .args(interp_args.as_slice())
         .group(metrics_group.clone())
@@ -62,6 +72,7 @@ pub fn cmd_parser<'a, 'b>() -> App<'a, 'b> {
         .arg(verbose.clone())
         .group(palette_input_group.clone())
         .args(palette_input_args.as_slice())
+        .args(alpha_args.as_slice())
         .groups(interp_groups.as_slice())
         .args(interp_args.as_slice())
         .groups(dither_groups.as_slice())
@@ -75,6 +86,61 @@ pub fn cmd_parser<'a, 'b>() -> App<'a, 'b> {
                 .help("Sets output image file; default: plot.png")
                 .takes_value(true)
         );
+    let convert = SubCommand::with_name("convert")
+        .about("Writes the palette in another format.")
+        .arg(verbose.clone())
+        .group(palette_input_group.clone())
+        .args(palette_input_args.as_slice())
+        .args(alpha_args.as_slice())
+        .groups(interp_groups.as_slice())
+        .args(interp_args.as_slice())
+        .groups(convert_groups.as_slice())
+        .args(convert_args.as_slice())
+        .arg(
+            Arg::with_name("outfile")
+                .short("o")
+                .long("out")
+                .value_name("FILE")
+                .help("Sets output palette file")
+                .takes_value(true)
+                .required(true)
+        );
+    let usage = SubCommand::with_name("usage")
+        .about("Counts how often palette colours are used in an image.")
+        .arg(verbose.clone())
+        .group(palette_input_group.clone())
+        .args(palette_input_args.as_slice())
+        .args(alpha_args.as_slice())
+        .groups(interp_groups.as_slice())
+        .args(interp_args.as_slice())
+        .args(usage_args.as_slice());
+    let remap = SubCommand::with_name("remap")
+        .about("Recolours an image made with one palette into the provided palette.")
+        .arg(verbose.clone())
+        .group(palette_input_group.clone())
+        .args(palette_input_args.as_slice())
+        .args(alpha_args.as_slice())
+        .groups(interp_groups.as_slice())
+        .args(interp_args.as_slice())
+        .groups(remap_groups.as_slice())
+        .args(remap_args.as_slice());
+    let coverage = SubCommand::with_name("coverage")
+        .about("Shows how well the palette represents an image.")
+        .arg(verbose.clone())
+        .group(palette_input_group.clone())
+        .args(palette_input_args.as_slice())
+        .args(alpha_args.as_slice())
+        .groups(interp_groups.as_slice())
+        .args(interp_args.as_slice())
+        .args(coverage_args.as_slice());
+    let shade = SubCommand::with_name("shade")
+        .about("Makes lit variants of the palette colours.")
+        .arg(verbose.clone())
+        .group(palette_input_group.clone())
+        .args(palette_input_args.as_slice())
+        .groups(interp_groups.as_slice())
+        .args(interp_args.as_slice())
+        .args(shade_args.as_slice());
 
     let app = App::new("censor")
         .version(VERSION)
@@ -82,13 +148,19 @@ pub fn cmd_parser<'a, 'b>() -> App<'a, 'b> {
         .subcommand(daemon)
         .subcommand(analyse)
         .subcommand(compute)
-        .subcommand(dither);
+        .subcommand(dither)
+        .subcommand(convert)
+        .subcommand(usage)
+        .subcommand(remap)
+        .subcommand(coverage)
+        .subcommand(shade);
 
     return app;
 }
 
 pub fn daemon_parser<'a, 'b>() -> App<'a, 'b> {
     let (palette_input_group, palette_input_args) = palette_input_args();
+    let alpha_args = alpha_args();
     let image_input_args = image_input_args();
     let (dither_groups, dither_args) = dither_args();
     let (interp_groups, interp_args) = interpretation_args();
@@ -99,6 +171,7 @@ pub fn daemon_parser<'a, 'b>() -> App<'a, 'b> {
         .about("Produces a plot with palette analysis.")
         .group(palette_input_group.clone())
         .args(palette_input_args.as_slice())
+        .args(alpha_args.as_slice())
         .groups(interp_groups.as_slice())
         .args(interp_args.as_slice())
         .groups(repr_groups.as_slice())
@@ -116,6 +189,7 @@ pub fn daemon_parser<'a, 'b>() -> App<'a, 'b> {
         .about("Computes palette metrics.")
         .group(palette_input_group.clone())
         .args(palette_input_args.as_slice())
+        .args(alpha_args.as_slice())
         .groups(interp_groups.as_slice())
         .args(interp_args.as_slice())
         .group(metrics_group.clone())
@@ -124,6 +198,7 @@ pub fn daemon_parser<'a, 'b>() -> App<'a, 'b> {
         .about("Reduces image's colours using the provided palette.")
         .group(palette_input_group.clone())
         .args(palette_input_args.as_slice())
+        .args(alpha_args.as_slice())
         .groups(interp_groups.as_slice())
         .args(interp_args.as_slice())
         .groups(dither_groups.as_slice())
@@ -153,7 +228,7 @@ fn palette_input_args<'a, 'b>() -> (ArgGroup<'a>, Vec<Arg<'a, 'b>>) {
     let group = ArgGroup::with_name("palette_input")
         .multiple(false)
         .required(true)
-        .args(&["colours", "hexfile", "imagefile", "lospec"]);
+        .args(&["colours", "hexfile", "imagefile", "lospec", "swatchfile", "indexedfile"]);
     let args = vec![
         Arg::with_name("colours")
             .short("c")
@@ -178,17 +253,54 @@ fn palette_input_args<'a, 'b>() -> (ArgGroup<'a>, Vec<Arg<'a, 'b>>) {
             .long("lospec")
             .value_name("SLUG")
             .help("Loads input colours from https://lospec.com/palette-list/SLUG")
-            .takes_value(true)
+            .takes_value(true),
+        Arg::with_name("swatchfile")
+            .short("s")
+            .long("swatches")
+            .value_name("FILE")
+            .help("Reads input colours from uniform swatch cells of the specified image in reading order")
+            .takes_value(true),
+        Arg::with_name("indexedfile")
+            .short("x")
+            .long("indexed")
+            .value_name("FILE")
+            .help("Reads input colours from the colour table of the specified indexed PNG, GIF or BMP image")
+            .takes_value(true),
+        Arg::with_name("lospec_url")
+            .long("lospec-url")
+            .value_name("URL")
+            .help("Downloads Lospec palettes from URL/SLUG.csv instead, e.g. from a local mirror")
+            .takes_value(true),
+        Arg::with_name("offline")
+            .long("offline")
+            .help("Uses only previously downloaded Lospec palettes")
     ];
     return (group, args);
 }
 
+fn alpha_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    let args = vec![
+        Arg::with_name("alpha_threshold")
+            .long("alpha-threshold")
+            .value_name("N")
+            .help("Treats pixels with alpha below N as transparent. Default: 255")
+            .takes_value(true),
+        Arg::with_name("background")
+            .long("background")
+            .value_name("HEX")
+            .help("Composites partially transparent pixels against the specified colour")
+            .takes_value(true)
+    ];
+    return args;
+}
+
 fn image_input_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
     let args = vec![
         Arg::with_name("imageinput")
             .value_name("FILE")
-            .help("Loads specified image")
+            .help("Loads specified images; directories and glob patterns are expanded")
             .required(true)
+            .multiple(true)
             .index(1)
     ];
     return args;
@@ -263,7 +375,9 @@ fn dither_args<'a, 'b>() -> (Vec<ArgGroup<'a>>, Vec<Arg<'a, 'b>>) {
         ArgGroup::with_name("dither_method")
             .multiple(false)
             .required(false)
-            .args(&["nodither", "bayer", "whitenoise", "bluenoise"])
+            .args(&["nodither", "bayer", "whitenoise", "bluenoise", "pattern",
+                    "dispersed", "halftone", "lines", "crosshatch", "ign", "threshold_map",
+                    "floyd_steinberg", "atkinson", "jarvis", "stucki", "sierra", "burkes"])
     ];
     let args = vec![
         Arg::with_name("nodither")
@@ -284,11 +398,351 @@ fn dither_args<'a, 'b>() -> (Vec<ArgGroup<'a>>, Vec<Arg<'a, 'b>>) {
             .long("bluenoise")
             .value_name("WxH")
             .help("Uses a blue noise matrix of size WxH for ordered dithering")
+            .takes_value(true),
+        Arg::with_name("dispersed")
+            .long("dispersed")
+            .value_name("N")
+            .help("Uses a Bayer-like dispersed dot matrix of size NxN for ordered dithering")
+            .takes_value(true),
+        Arg::with_name("halftone")
+            .long("halftone")
+            .value_name("N")
+            .help("Uses a clustered dot halftone screen with NxN cells")
+            .takes_value(true),
+        Arg::with_name("lines")
+            .long("lines")
+            .value_name("N")
+            .help("Uses a line screen with a period of N pixels")
+            .takes_value(true),
+        Arg::with_name("line_direction")
+            .long("line-direction")
+            .value_name("DIRECTION")
+            .help("Sets the direction of the line screen. Default: horizontal")
+            .possible_values(&["horizontal", "vertical", "diagonal"])
+            .takes_value(true),
+        Arg::with_name("crosshatch")
+            .long("crosshatch")
+            .value_name("N")
+            .help("Uses a cross-hatch screen with a period of N pixels")
+            .takes_value(true),
+        Arg::with_name("ign")
+            .long("ign")
+            .value_name("WxH")
+            .help("Uses interleaved gradient noise of size WxH for ordered dithering")
+            .takes_value(true),
+        Arg::with_name("threshold_map")
+            .long("threshold-map")
+            .value_name("FILE")
+            .help("Uses a greyscale image as the threshold matrix, darker pixels first")
+            .takes_value(true),
+        Arg::with_name("pattern")
+            .long("pattern")
+            .value_name("N")
+            .help("Uses pattern dithering with a Bayer matrix of size 2^N")
+            .takes_value(true),
+        Arg::with_name("chromatic")
+            .long("chromatic")
+            .help("Spreads ordered dithering towards the second nearest colour instead of along lightness"),
+        Arg::with_name("spread")
+            .long("spread")
+            .value_name("A")
+            .help("Scales the ordered dithering spread. Default: 1")
+            .takes_value(true),
+        Arg::with_name("seed")
+            .long("seed")
+            .value_name("N")
+            .help("Seeds the random threshold matrices (white and blue noise)")
+            .takes_value(true),
+        Arg::with_name("floyd_steinberg")
+            .long("floyd-steinberg")
+            .help("Uses Floyd-Steinberg error diffusion"),
+        Arg::with_name("atkinson")
+            .long("atkinson")
+            .help("Uses Atkinson error diffusion"),
+        Arg::with_name("jarvis")
+            .long("jarvis")
+            .help("Uses Jarvis-Judice-Ninke error diffusion"),
+        Arg::with_name("stucki")
+            .long("stucki")
+            .help("Uses Stucki error diffusion"),
+        Arg::with_name("sierra")
+            .long("sierra")
+            .help("Uses Sierra error diffusion"),
+        Arg::with_name("burkes")
+            .long("burkes")
+            .help("Uses Burkes error diffusion"),
+        Arg::with_name("serpentine")
+            .long("serpentine")
+            .help("Scans every other row right to left for error diffusion"),
+        Arg::with_name("strength")
+            .long("strength")
+            .value_name("S")
+            .help("Scales the diffused error by S. Default: 1")
+            .takes_value(true),
+        Arg::with_name("compare")
+            .long("compare")
+            .value_name("METHODS")
+            .help(formatcp!("Renders a sheet comparing dithering methods (comma-separated) \
+                             and prints their mean and 95th percentile error. Default: {}",
+                            COMPARE_DEFAULT))
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true),
+        Arg::with_name("hardware")
+            .long("hardware")
+            .value_name("TARGET")
+            .help("Limits colours per block like retro hardware: NES 16x16 blocks with 4 \
+                   sub-palettes, ZX Spectrum 8x8 with 2 colours, C64 multicolour 4x8 cells, \
+                   Game Boy Color 8x8 tiles with 8 palettes")
+            .possible_values(&["nes", "zx", "c64", "gbc"])
+            .takes_value(true),
+        Arg::with_name("subpalettes")
+            .long("subpalettes")
+            .value_name("LIST")
+            .help("Uses fixed sub-palettes of palette indices, e.g. 0,1,2,3;0,4,5,6")
+            .requires("hardware")
+            .takes_value(true),
+        Arg::with_name("assignment")
+            .long("assignment")
+            .value_name("FILE")
+            .help("Writes the per-block sub-palettes as JSON here; {name} is replaced with the \
+                   input file name. Default: the output file with .json extension")
+            .requires("hardware")
+            .takes_value(true),
+        Arg::with_name("resize")
+            .long("resize")
+            .value_name("WxH")
+            .help("Downscales the image to WxH by area averaging before dithering")
+            .takes_value(true),
+        Arg::with_name("scale")
+            .long("scale")
+            .value_name("N")
+            .help("Enlarges the output N times with crisp pixels. Default: 1")
+            .takes_value(true),
+        Arg::with_name("subset")
+            .long("subset")
+            .value_name("K")
+            .help("Dithers with the K palette colours that represent the image best and prints their indices")
+            .conflicts_with_all(&["hardware", "compare"])
+            .takes_value(true),
+        Arg::with_name("usage")
+            .long("usage")
+            .value_name("FILE")
+            .help("Writes palette colour usage of the result as JSON (.json) or CSV; {name} is replaced with the input file name")
+            .conflicts_with("compare")
+            .takes_value(true),
+        Arg::with_name("value_check")
+            .long("value-check")
+            .value_name("FILE")
+            .help("Writes the lightness of the source and the result side by side with their difference; {name} is replaced with the input file name")
+            .conflicts_with("compare")
+            .takes_value(true),
+        Arg::with_name("output_dir")
+            .long("output-dir")
+            .value_name("DIR")
+            .help("Writes the dithered images into DIR, named by --name-template")
+            .takes_value(true),
+        Arg::with_name("name_template")
+            .long("name-template")
+            .value_name("TEMPLATE")
+            .help("Names output files in --output-dir, {name} being the input file name without extension. Default: {name}.png")
+            .requires("output_dir")
+            .takes_value(true),
+        Arg::with_name("threads")
+            .long("threads")
+            .value_name("N")
+            .help("Dithers with N threads. Default: all available cores")
+            .takes_value(true),
+        Arg::with_name("frame_delay")
+            .long("frame-delay")
+            .value_name("MS")
+            .help("Sets the frame duration for numbered frame sequences. Default: 100")
+            .takes_value(true),
+        Arg::with_name("paletted")
+            .long("paletted")
+            .help("Writes an indexed PNG with the palette in its original order"),
+        Arg::with_name("transparent")
+            .long("transparent")
+            .help("Writes an RGBA image keeping transparent pixels transparent")
+    ];
+    return (groups, args);
+}
+
+fn convert_args<'a, 'b>() -> (Vec<ArgGroup<'a>>, Vec<Arg<'a, 'b>>) {
+    let groups = vec![];
+    let args = vec![
+        Arg::with_name("format")
+            .long("format")
+            .value_name("FORMAT")
+            .help("Sets output format; guessed from the file extension by default")
+            .possible_values(&["hex", "gpl", "pal", "txt", "ase", "png", "png8"])
+            .takes_value(true),
+        Arg::with_name("sort")
+            .long("sort")
+            .value_name("KEY")
+            .help("Reorders the colours by CAM16UCS lightness, hue or chroma")
+            .possible_values(&["lightness", "hue", "chroma"])
+            .takes_value(true),
+        Arg::with_name("reverse")
+            .long("reverse")
+            .help("Reverses the order of the colours"),
+        Arg::with_name("dedup")
+            .long("dedup")
+            .help("Removes repeated colours keeping the first occurrence")
+    ];
+    return (groups, args);
+}
+
+fn usage_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    let args = vec![
+        Arg::with_name("imageinput")
+            .value_name("FILE")
+            .help("Loads the specified image; other colours are mapped to the nearest palette colour")
+            .required(true)
+            .index(1),
+        Arg::with_name("outfile")
+            .short("o")
+            .long("out")
+            .value_name("FILE")
+            .help("Writes the report as JSON (.json) or CSV; default: CSV to the standard output")
+            .takes_value(true),
+        Arg::with_name("chart")
+            .long("chart")
+            .value_name("FILE")
+            .help("Draws a histogram of the colour usage into the specified image")
+            .takes_value(true)
+    ];
+    return args;
+}
+
+fn remap_args<'a, 'b>() -> (Vec<ArgGroup<'a>>, Vec<Arg<'a, 'b>>) {
+    let groups = vec![
+        ArgGroup::with_name("source_palette")
+            .multiple(false)
+            .args(&["from_colours", "from_hexfile", "from_lospec", "from_indexed"])
+    ];
+    let args = vec![
+        Arg::with_name("imageinput")
+            .value_name("FILE")
+            .help("Loads the image to recolour")
+            .required(true)
+            .index(1),
+        Arg::with_name("outfile")
+            .short("o")
+            .long("out")
+            .value_name("FILE")
+            .help("Sets output image file")
+            .takes_value(true)
+            .required(true),
+        Arg::with_name("from_colours")
+            .long("from-colours")
+            .value_name("LIST")
+            .help("Sets the palette of the image to the list of comma-separated hex values. Default: the colours used by the image")
+            .takes_value(true),
+        Arg::with_name("from_hexfile")
+            .long("from-hexfile")
+            .value_name("FILE")
+            .help("Reads the palette of the image from the specified file with newline-separated hex values")
+            .takes_value(true),
+        Arg::with_name("from_lospec")
+            .long("from-lospec")
+            .value_name("SLUG")
+            .help("Loads the palette of the image from https://lospec.com/palette-list/SLUG")
+            .takes_value(true),
+        Arg::with_name("from_indexed")
+            .long("from-indexed")
+            .value_name("FILE")
+            .help("Reads the palette of the image from the colour table of the specified indexed image")
+            .takes_value(true),
+        Arg::with_name("mapping")
+            .long("mapping")
+            .value_name("FILE")
+            .help("Reads fixed correspondences from FILE, one pair of source and target hex colours per line")
+            .takes_value(true),
+        Arg::with_name("value_check")
+            .long("value-check")
+            .value_name("FILE")
+            .help("Writes the lightness of the source and the recoloured image side by side with their difference")
             .takes_value(true)
     ];
     return (groups, args);
 }
 
+fn coverage_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    let args = vec![
+        Arg::with_name("imageinput")
+            .value_name("FILE")
+            .help("Loads the reference image")
+            .required(true)
+            .index(1),
+        Arg::with_name("outfile")
+            .short("o")
+            .long("out")
+            .value_name("FILE")
+            .help("Sets output heatmap file; default: plot.png")
+            .takes_value(true),
+        Arg::with_name("threshold")
+            .long("threshold")
+            .value_name("D")
+            .help("Sets the CAM16UCS distance above which a pixel is not covered. Default: 10")
+            .takes_value(true),
+        Arg::with_name("report")
+            .long("report")
+            .value_name("FILE")
+            .help("Writes the statistics, regions and hue bands as JSON")
+            .takes_value(true),
+        Arg::with_name("value_check")
+            .long("value-check")
+            .value_name("FILE")
+            .help("Writes the lightness of the source and the image in the nearest palette colours side by side with their difference")
+            .takes_value(true)
+    ];
+    return args;
+}
+
+fn shade_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    let args = vec![
+        Arg::with_name("light")
+            .long("light")
+            .value_name("HEX")
+            .help("Sets the light colour. Default: ffffff")
+            .takes_value(true),
+        Arg::with_name("levels")
+            .long("levels")
+            .value_name("LIST")
+            .help("Sets comma-separated light intensities. Default: 0.25,0.5,0.75,1")
+            .takes_value(true),
+        Arg::with_name("model")
+            .long("model")
+            .value_name("MODEL")
+            .help("Multiplies colours by the light in linear light or adapts them to its white point. Default: multiply")
+            .possible_values(&["multiply", "adapt"])
+            .takes_value(true),
+        Arg::with_name("snap")
+            .long("snap")
+            .help("Replaces the lit colours with the nearest palette colours"),
+        Arg::with_name("export")
+            .long("export")
+            .value_name("FILE")
+            .help("Writes the lit colours of all levels into a palette file")
+            .takes_value(true),
+        Arg::with_name("format")
+            .long("format")
+            .value_name("FORMAT")
+            .help("Sets the format of --export; guessed from the file extension by default")
+            .possible_values(&["hex", "gpl", "pal", "txt", "ase", "png", "png8"])
+            .requires("export")
+            .takes_value(true),
+        Arg::with_name("outfile")
+            .short("o")
+            .long("out")
+            .value_name("FILE")
+            .help("Sets output grid image file; default: plot.png")
+            .takes_value(true)
+    ];
+    return args;
+}
+
 fn verbose_arg<'a, 'b>() -> Arg<'a, 'b> {
     Arg::with_name("verbose")
         .short("v")
@@ -107,8 +107,17 @@ impl Palette {
         return argmin;
     }
     pub fn nearest(&self, x: CAM16UCS) -> RGB255 {
+        return self.rgb[self.nearest_index(x)];
+    }
+    pub fn nearest_index(&self, x: CAM16UCS) -> usize {
         let (i, _) = self.tree.find_nearest(&x);
-        return self.rgb[i];
+        return i;
+    }
+    /// The nearest colour other than the one at `first`.
+    pub fn second_nearest_index(&self, x: CAM16UCS, first: usize) -> usize {
+        return Self::minimise(&self.cam16, |i, y| {
+            if i == first { f32::MAX } else { CAM16UCS::dist(&x, &y) }
+        });
     }
     pub fn nearest_limatch(&self, x: CAM16UCS, t: f32) -> RGB255 {
         let mut min = f32::MAX;
@@ -135,17 +144,10 @@ impl Palette {
         let o = CIExy::from(CIEXYZ::new(ill.X_w, ill.Y_w, ill.Z_w));
         for i in 0..self.n {
             let xy = CIExy::from(self.xyz[i]);
-            match xy.try_nearest_spectral(o) {
-                Some(Wavelength{wl}) => {
-                    let k = PackedF32(wl);
-                    if !stats.contains_key(&k) {
-                        stats.insert(k, 0.);
-                    }
-                    let weight = (self.cam16[i].C / 100.).clip(0., 1.);
-                    stats.insert(k, stats[&k] + weight);
-                    points.insert(i, wl);
-                }
-                None => {}
+            if let Some(Wavelength{wl}) = xy.try_nearest_spectral(o) {
+                let weight = (self.cam16[i].C / 100.).clip(0., 1.);
+                *stats.entry(PackedF32(wl)).or_insert(0.) += weight;
+                points.insert(i, wl);
             }
         }
         let norm: f32 = stats.values().sum();
@@ -160,17 +162,10 @@ impl Palette {
         let mut stats = HashMap::new();
         let mut points = HashMap::new();
         for i in 0..self.n {
-            match CIEuv::from(self.xyz[i]).CCT() {
-                Some((T, dist)) => {
-                    let k = PackedF32(T);
-                    if !stats.contains_key(&k) {
-                        stats.insert(k, 0.);
-                    }
-                    let weight = 1. - dist * 20.;
-                    stats.insert(k, stats[&k] + weight);
-                    points.insert(i, T);
-                }
-                None => {}
+            if let Some((T, dist)) = CIEuv::from(self.xyz[i]).CCT() {
+                let weight = 1. - dist * 20.;
+                *stats.entry(PackedF32(T)).or_insert(0.) += weight;
+                points.insert(i, T);
             }
         }
         let norm: f32 = stats.values().sum();
@@ -185,7 +180,7 @@ impl Palette {
         fn score_added(cam16: &Vec<CAM16UCS>, x: CAM16UCS) -> f32 {
             let mut min = f32::MAX;
             for y in cam16.iter() {
-                let d = CAM16UCS::dist(&x, &y);
+                let d = CAM16UCS::dist(&x, y);
                 if d < min {
                     min = d;
                 }
@@ -0,0 +1,425 @@
+use crate::colour::*;
+use crate::palette::Palette;
+use crate::loader::{
+    parse_hex, gif_colour_tables, bmp_colour_table_span, ColourTableSpan, LoadError
+};
+
+use img_parts::png::{Png, PngChunk};
+
+use std::collections::HashMap;
+
+#[derive(Debug)]
+pub enum RemapError {
+    InvalidMappingLine(usize),
+    InvalidMappingColour(usize, String),
+    NotInSourcePalette(RGB255),
+    NotInTargetPalette(RGB255),
+    InvalidColourTable
+}
+impl std::fmt::Display for RemapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidMappingLine(line) => {
+                write!(f, "Mapping line {}: expected a source and a target hex colour", line)
+            }
+            Self::InvalidMappingColour(line, ref e) => {
+                write!(f, "Mapping line {}: {}", line, e)
+            }
+            Self::NotInSourcePalette(c) => {
+                write!(f, "Colour {} is not in the source palette", c.hex())
+            }
+            Self::NotInTargetPalette(c) => {
+                write!(f, "Colour {} is not in the target palette", c.hex())
+            }
+            Self::InvalidColourTable => { write!(f, "Malformed colour table") }
+        }
+    }
+}
+
+/// Correspondence from the colours of one palette to the colours of another.
+pub struct PaletteMapping {
+    /// Target palette index of every source palette colour.
+    pub targets: Vec<usize>,
+    /// Whether the target was given by hand.
+    pub fixed: Vec<bool>
+}
+impl PaletteMapping {
+    /// Assigns target colours to the source colours minimising the total CAM16UCS distance.
+    /// Each target colour is used at most ceil(n_from / n_to) times (counting the fixed
+    /// ones), so with enough target colours every source colour gets its own one.
+    pub fn optimal(from: &Palette, to: &Palette, fixed: &HashMap<usize, usize>) -> Self {
+        let uses = from.n.div_ceil(to.n);
+        let mut slots = vec![uses; to.n];
+        for &j in fixed.values() {
+            slots[j] = slots[j].saturating_sub(1);
+        }
+        let columns: Vec<usize> = (0..to.n)
+            .flat_map(|j| std::iter::repeat_n(j, slots[j]))
+            .collect();
+        let rows: Vec<usize> = (0..from.n).filter(|i| !fixed.contains_key(i)).collect();
+        let cost: Vec<Vec<f64>> = rows.iter()
+            .map(|&i| columns.iter()
+                .map(|&j| CAM16UCS::dist(&from.cam16[i], &to.cam16[j]) as f64)
+                .collect())
+            .collect();
+        let assigned = Self::assignment(&cost);
+
+        let mut targets = vec![0; from.n];
+        let mut is_fixed = vec![false; from.n];
+        for (&i, &j) in fixed.iter() {
+            targets[i] = j;
+            is_fixed[i] = true;
+        }
+        for (k, &i) in rows.iter().enumerate() {
+            targets[i] = columns[assigned[k]];
+        }
+        return Self { targets, fixed: is_fixed };
+    }
+
+    /// Minimum cost assignment of every row to a distinct column (rows <= columns),
+    /// found with the Hungarian algorithm in O(rows^2 columns).
+    fn assignment(cost: &Vec<Vec<f64>>) -> Vec<usize> {
+        let n = cost.len();
+        if n == 0 { return vec![]; }
+        let m = cost[0].len();
+        // 1-based potentials and matching, column 0 being a sentinel.
+        let mut u = vec![0.; n + 1];
+        let mut v = vec![0.; m + 1];
+        let mut p = vec![0; m + 1];
+        let mut way = vec![0; m + 1];
+        for i in 1..=n {
+            p[0] = i;
+            let mut j0 = 0;
+            let mut minv = vec![f64::INFINITY; m + 1];
+            let mut used = vec![false; m + 1];
+            loop {
+                used[j0] = true;
+                let i0 = p[j0];
+                let mut delta = f64::INFINITY;
+                let mut j1 = 0;
+                for j in 1..=m {
+                    if used[j] { continue; }
+                    let cur = cost[i0 - 1][j - 1] - u[i0] - v[j];
+                    if cur < minv[j] {
+                        minv[j] = cur;
+                        way[j] = j0;
+                    }
+                    if minv[j] < delta {
+                        delta = minv[j];
+                        j1 = j;
+                    }
+                }
+                for j in 0..=m {
+                    if used[j] {
+                        u[p[j]] += delta;
+                        v[j] -= delta;
+                    } else {
+                        minv[j] -= delta;
+                    }
+                }
+                j0 = j1;
+                if p[j0] == 0 { break; }
+            }
+            loop {
+                let j1 = way[j0];
+                p[j0] = p[j1];
+                j0 = j1;
+                if j0 == 0 { break; }
+            }
+        }
+        let mut result = vec![0; n];
+        for j in 1..=m {
+            if p[j] != 0 {
+                result[p[j] - 1] = j - 1;
+            }
+        }
+        return result;
+    }
+
+    /// Reads hand-made correspondences: one `SOURCE TARGET` pair of hex colours per line.
+    /// Text after `;` is a comment.
+    pub fn parse_overrides(text: &str, from: &Palette, to: &Palette)
+                -> Result<HashMap<usize, usize>, RemapError> {
+        let mut fixed = HashMap::new();
+        for (k, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default().trim();
+            if line.is_empty() { continue; }
+            let colours: Vec<&str> = line.split_whitespace().collect();
+            if colours.len() != 2 {
+                return Err(RemapError::InvalidMappingLine(k + 1));
+            }
+            let parse = |s: &str| {
+                parse_hex(s.trim_start_matches('#').into())
+                    .map_err(|e| RemapError::InvalidMappingColour(k + 1, e.to_string()))
+            };
+            let (a, b) = (parse(colours[0])?, parse(colours[1])?);
+            let i = from.rgb.iter().position(|&c| c == a)
+                .ok_or(RemapError::NotInSourcePalette(a))?;
+            let j = to.rgb.iter().position(|&c| c == b)
+                .ok_or(RemapError::NotInTargetPalette(b))?;
+            fixed.insert(i, j);
+        }
+        return Ok(fixed);
+    }
+
+    /// The mapping in the format of `parse_overrides`, hand-made pairs marked.
+    pub fn to_text(&self, from: &Palette, to: &Palette) -> String {
+        let mut s = String::new();
+        for (i, &j) in self.targets.iter().enumerate() {
+            s += &format!("{} {}", from.rgb[i].hex(), to.rgb[j].hex());
+            if self.fixed[i] { s += " ; fixed"; }
+            s += "\n";
+        }
+        return s;
+    }
+}
+
+/// Colour replacement following a mapping; colours outside the source palette
+/// get the nearest target colour.
+pub struct Recolourer<'a> {
+    known: HashMap<RGB255, RGB255>,
+    to: &'a Palette,
+    lut: &'a CAM16Lut
+}
+impl<'a> Recolourer<'a> {
+    pub fn new(mapping: &PaletteMapping, from: &Palette, to: &'a Palette, lut: &'a CAM16Lut) -> Self {
+        let mut known = HashMap::new();
+        for (i, &j) in mapping.targets.iter().enumerate() {
+            known.entry(from.rgb[i]).or_insert(to.rgb[j]);
+        }
+        Self { known, to, lut }
+    }
+    pub fn get(&mut self, c: RGB255) -> RGB255 {
+        let (to, lut) = (self.to, self.lut);
+        *self.known.entry(c).or_insert_with(|| to.nearest(lut.of(c.into())))
+    }
+
+    /// Recolours the colour table of an indexed PNG, GIF or BMP file, keeping
+    /// the pixel indices and everything else. Returns `None` for other images.
+    pub fn colour_table(&mut self, data: &[u8]) -> Result<Option<Vec<u8>>, RemapError> {
+        if data.starts_with(b"\x89PNG") {
+            // Colour type 3 in IHDR; other PNGs may only suggest a palette.
+            if data.get(25) != Some(&3) { return Ok(None); }
+            let mut png = Png::from_bytes(data.to_vec().into())
+                .map_err(|_| RemapError::InvalidColourTable)?;
+            for chunk in png.chunks_mut().iter_mut() {
+                if chunk.kind() == *b"PLTE" {
+                    let mut table = chunk.contents().to_vec();
+                    self.recolour_table(&mut table, 3, false)?;
+                    *chunk = PngChunk::new(*b"PLTE", table.into());
+                }
+            }
+            return Ok(Some(png.encoder().bytes().to_vec()));
+        }
+        if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+            let mut data = data.to_vec();
+            self.recolour_gif(&mut data)?;
+            return Ok(Some(data));
+        }
+        if data.starts_with(b"BM") {
+            let mut data = data.to_vec();
+            return match self.recolour_bmp(&mut data)? {
+                true => { Ok(Some(data)) }
+                false => { Ok(None) }
+            };
+        }
+        return Ok(None);
+    }
+
+    fn recolour_table(&mut self, table: &mut [u8], entry: usize, bgr: bool)
+                -> Result<(), RemapError> {
+        if !table.len().is_multiple_of(entry) {
+            return Err(RemapError::InvalidColourTable);
+        }
+        for c in table.chunks_exact_mut(entry) {
+            let (r, b) = if bgr { (2, 0) } else { (0, 2) };
+            let rgb = self.get(RGB255::new(c[r], c[1], c[b]));
+            c[r] = rgb.r;
+            c[1] = rgb.g;
+            c[b] = rgb.b;
+        }
+        return Ok(());
+    }
+
+    /// Recolours the global and all local colour tables.
+    fn recolour_gif(&mut self, data: &mut [u8]) -> Result<(), RemapError> {
+        let tables = gif_colour_tables(data).map_err(|_| RemapError::InvalidColourTable)?;
+        for table in tables {
+            self.recolour_span(data, table)?;
+        }
+        return Ok(());
+    }
+
+    /// Recolours the colour table of images with up to 8 bits per pixel.
+    fn recolour_bmp(&mut self, data: &mut [u8]) -> Result<bool, RemapError> {
+        let (table, bpp) = match bmp_colour_table_span(data) {
+            Ok(x) => { x }
+            Err(LoadError::NoColourTable) => { return Ok(false); }
+            Err(_) => { return Err(RemapError::InvalidColourTable); }
+        };
+        if bpp > 8 { return Ok(false); }
+        self.recolour_span(data, table)?;
+        return Ok(true);
+    }
+
+    fn recolour_span(&mut self, data: &mut [u8], table: ColourTableSpan)
+                -> Result<(), RemapError> {
+        let bytes = data.get_mut(table.range()).ok_or(RemapError::InvalidColourTable)?;
+        return self.recolour_table(bytes, table.entry, table.bgr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn palette(hex: &[u32]) -> Palette {
+        let ill = CAT16Illuminant::new(CIExy::from_T(5500.));
+        let rgb = hex.iter()
+            .map(|&x| RGB255::new((x >> 16) as u8, (x >> 8) as u8, x as u8))
+            .collect();
+        Palette::new(rgb, &ill, false)
+    }
+
+    /// Black to dark red and white to light cyan.
+    fn recolourer_test<F: Fn(&mut Recolourer, &mut Vec<u8>)>(data: &[u8], check: F) {
+        let from = palette(&[0x000000, 0xffffff]);
+        let to = palette(&[0x400000, 0xc0ffff]);
+        let ill = CAT16Illuminant::new(CIExy::from_T(5500.));
+        let lut = CAM16Lut::new(&ill);
+        let mapping = PaletteMapping { targets: vec![0, 1], fixed: vec![false, false] };
+        let mut recolourer = Recolourer::new(&mapping, &from, &to, &lut);
+        let mut data = data.to_vec();
+        check(&mut recolourer, &mut data);
+    }
+
+    #[test]
+    fn assignment_finds_optimum() {
+        // The greedy choice (row 1 to column 1) is not optimal.
+        let cost = vec![
+            vec![4., 1., 3.],
+            vec![2., 0., 5.],
+            vec![3., 2., 2.]
+        ];
+        assert_eq!(PaletteMapping::assignment(&cost), vec![1, 0, 2]);
+        // Fewer rows than columns leave the most expensive column unused.
+        let cost = vec![
+            vec![9., 2., 7., 8.],
+            vec![6., 4., 3., 7.]
+        ];
+        assert_eq!(PaletteMapping::assignment(&cost), vec![1, 2]);
+        assert!(PaletteMapping::assignment(&vec![]).is_empty());
+    }
+
+    #[test]
+    fn optimal_reuses_targets_and_keeps_fixed() {
+        let from = palette(&[0x000000, 0x202020, 0xe0e0e0, 0xffffff]);
+        let to = palette(&[0x000000, 0xffffff]);
+        let mapping = PaletteMapping::optimal(&from, &to, &HashMap::new());
+        assert_eq!(mapping.targets, vec![0, 0, 1, 1]);
+        assert_eq!(mapping.fixed, vec![false; 4]);
+
+        // Each target is used at most twice, so the fixed pair pushes
+        // the nearest remaining colour over to the other target.
+        let fixed: HashMap<usize, usize> = [(2, 0)].iter().cloned().collect();
+        let mapping = PaletteMapping::optimal(&from, &to, &fixed);
+        assert_eq!(mapping.targets, vec![0, 1, 0, 1]);
+        assert_eq!(mapping.fixed, vec![false, false, true, false]);
+    }
+
+    #[test]
+    fn parse_overrides_reports_lines() {
+        let from = palette(&[0x000000, 0xffffff]);
+        let to = palette(&[0x400000, 0xc0ffff]);
+        let text = "; comment\n\n#ffffff 400000 ; fixed\n000000 #c0ffff\n";
+        let fixed = PaletteMapping::parse_overrides(text, &from, &to).unwrap();
+        assert_eq!(fixed.get(&1), Some(&0));
+        assert_eq!(fixed.get(&0), Some(&1));
+
+        let error = |text: &str| PaletteMapping::parse_overrides(text, &from, &to).unwrap_err();
+        assert!(matches!(error("000000 400000\n000000\n"), RemapError::InvalidMappingLine(2)));
+        assert!(matches!(error("000000 400000 c0ffff"), RemapError::InvalidMappingLine(1)));
+        assert!(matches!(error("\n00000g 400000"), RemapError::InvalidMappingColour(2, _)));
+        assert!(matches!(error("123456 400000"), RemapError::NotInSourcePalette(_)));
+        assert!(matches!(error("000000 123456"), RemapError::NotInTargetPalette(_)));
+    }
+
+    #[test]
+    fn gif_tables_are_recoloured() {
+        let mut gif = vec![];
+        gif.extend_from_slice(b"GIF89a");
+        // 2x1 screen, global table of 2 colours
+        gif.extend_from_slice(&[2, 0, 1, 0, 0x80, 0, 0]);
+        gif.extend_from_slice(&[0, 0, 0, 255, 255, 255]);
+        // graphic control and comment extensions
+        gif.extend_from_slice(&[0x21, 0xf9, 4, 0, 10, 0, 0, 0]);
+        gif.extend_from_slice(&[0x21, 0xfe, 3, b'a', b'b', b'c', 2, b'd', b'e', 0]);
+        // image with a local table of 2 colours, reversed
+        gif.extend_from_slice(&[0x2c, 0, 0, 0, 0, 2, 0, 1, 0, 0x80]);
+        gif.extend_from_slice(&[255, 255, 255, 0, 0, 0]);
+        gif.extend_from_slice(&[2, 2, 0x4c, 0x01, 0]);
+        gif.push(0x3b);
+
+        let tables = gif_colour_tables(&gif).unwrap();
+        assert_eq!(tables.iter().map(|t| t.range()).collect::<Vec<_>>(), vec![13..19, 47..53]);
+        recolourer_test(&gif, |recolourer, data| {
+            let result = recolourer.colour_table(data).unwrap().unwrap();
+            let mut expected = gif.clone();
+            expected[13..19].copy_from_slice(&[0x40, 0, 0, 0xc0, 0xff, 0xff]);
+            expected[47..53].copy_from_slice(&[0xc0, 0xff, 0xff, 0x40, 0, 0]);
+            assert_eq!(result, expected);
+        });
+    }
+
+    /// A 2x1 image with 2 palette entries of `entry` bytes (BGR) after a `header_size` header.
+    fn bmp(header_size: usize, entry: usize) -> Vec<u8> {
+        let mut bmp = vec![];
+        let table = 2 * entry;
+        let offset = 14 + header_size + table;
+        bmp.extend_from_slice(b"BM");
+        bmp.extend_from_slice(&((offset + 4) as u32).to_le_bytes());
+        bmp.extend_from_slice(&[0; 4]);
+        bmp.extend_from_slice(&(offset as u32).to_le_bytes());
+        bmp.extend_from_slice(&(header_size as u32).to_le_bytes());
+        if header_size == 12 {
+            // OS/2 header: 16-bit sizes, 1 bit per pixel
+            bmp.extend_from_slice(&[2, 0, 1, 0, 1, 0, 1, 0]);
+        } else {
+            // 8 bits per pixel, 2 colours used
+            bmp.extend_from_slice(&[2, 0, 0, 0, 1, 0, 0, 0, 1, 0, 8, 0]);
+            bmp.extend_from_slice(&[0; 16]);
+            bmp.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 0]);
+        }
+        assert_eq!(bmp.len(), 14 + header_size);
+        for &c in [0u8, 255].iter() {
+            bmp.extend_from_slice(&[c; 3]);
+            if entry == 4 { bmp.push(0); }
+        }
+        // pixel data: indices 1 and 0, padded to 4 bytes
+        if header_size == 12 {
+            bmp.extend_from_slice(&[0b1000_0000, 0, 0, 0]);
+        } else {
+            bmp.extend_from_slice(&[1, 0, 0, 0]);
+        }
+        return bmp;
+    }
+
+    #[test]
+    fn bmp_tables_are_recoloured() {
+        for &(header_size, entry) in [(40, 4), (12, 3)].iter() {
+            let bmp = bmp(header_size, entry);
+            let (table, bpp) = bmp_colour_table_span(&bmp).unwrap();
+            assert_eq!((table.offset, table.count, table.entry), (14 + header_size, 2, entry));
+            assert!(bpp <= 8);
+            recolourer_test(&bmp, |recolourer, data| {
+                let result = recolourer.colour_table(data).unwrap().unwrap();
+                let table = 14 + header_size;
+                let pixels = table + 2 * entry;
+                assert_eq!(result[..table], bmp[..table]);
+                assert_eq!(result[pixels..], bmp[pixels..]);
+                assert_eq!(result[table..table + 3], [0, 0, 0x40]);
+                assert_eq!(result[table + entry..table + entry + 3], [0xff, 0xff, 0xc0]);
+            });
+        }
+    }
+}
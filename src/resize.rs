@@ -0,0 +1,84 @@
+use crate::colour::*;
+use crate::cache::PlotData;
+
+/// Source pixels covering every destination pixel along one axis,
+/// with the covered fraction of each.
+fn area_weights(from: usize, to: usize) -> Vec<Vec<(usize, f32)>> {
+    let ratio = from as f32 / to as f32;
+    let mut weights = vec![];
+    for i in 0..to {
+        let a = i as f32 * ratio;
+        let b = (i + 1) as f32 * ratio;
+        let mut row = vec![];
+        let mut j = a.floor() as usize;
+        while (j as f32) < b && j < from {
+            let covered = f32::min(b, (j + 1) as f32) - f32::max(a, j as f32);
+            if covered > 0. {
+                row.push((j, covered / ratio));
+            }
+            j += 1;
+        }
+        weights.push(row);
+    }
+    return weights;
+}
+
+/// Resizes an image to `w`x`h` by averaging the covered area in linear light.
+///
+/// A pixel stays transparent if transparent pixels cover at least half of its area,
+/// otherwise only the opaque ones are averaged.
+pub fn resize_area(data: &Vec<Vec<Option<RGB255>>>, w: usize, h: usize)
+            -> Vec<Vec<Option<RGB1>>> {
+    let src_h = data.len();
+    let src_w = if src_h > 0 { data[0].len() } else { 0 };
+    let linear: Vec<Vec<Option<[f32; 3]>>> = data.iter()
+        .map(|row| row.iter()
+            .map(|opt| opt.map(|rgb| {
+                let c = RGB1::from(rgb);
+                [ungamma(c.r), ungamma(c.g), ungamma(c.b)]
+            }))
+            .collect())
+        .collect();
+    let xs = area_weights(src_w, w);
+    let ys = area_weights(src_h, h);
+
+    let mut output = vec![vec![None; w]; h];
+    for (y, wy) in ys.iter().enumerate() {
+        for (x, wx) in xs.iter().enumerate() {
+            let mut sum = [0f32; 3];
+            let mut opaque = 0.;
+            for &(j, ay) in wy.iter() {
+                for &(i, ax) in wx.iter() {
+                    if let Some(c) = linear[j][i] {
+                        let a = ax * ay;
+                        for k in 0..3 {
+                            sum[k] += c[k] * a;
+                        }
+                        opaque += a;
+                    }
+                }
+            }
+            if opaque > 0.5 {
+                output[y][x] = Some(RGB1 {
+                    r: gamma(sum[0] / opaque),
+                    g: gamma(sum[1] / opaque),
+                    b: gamma(sum[2] / opaque)
+                });
+            }
+        }
+    }
+    return output;
+}
+
+/// Enlarges an image `n` times, repeating every pixel.
+pub fn scale_nearest<T: Copy>(data: &PlotData<T>, n: usize) -> PlotData<T> {
+    let scaled = data.data.iter()
+        .flat_map(|row| {
+            let wide: Vec<Option<T>> = row.iter()
+                .flat_map(|&px| std::iter::repeat_n(px, n))
+                .collect();
+            std::iter::repeat_n(wide, n)
+        })
+        .collect();
+    return PlotData::new(scaled);
+}
@@ -0,0 +1,137 @@
+use crate::colour::*;
+use crate::palette::Palette;
+
+/// How a light changes the colours it falls on.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LightModel {
+    /// Multiplies linear sRGB by the light colour.
+    Multiply,
+    /// Adapts the colours from the sRGB white to the light colour (von Kries in CAT16 space).
+    Adapt
+}
+impl LightModel {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "multiply" => { Some(Self::Multiply) }
+            "adapt" => { Some(Self::Adapt) }
+            _ => { None }
+        }
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Multiply => { "multiply" }
+            Self::Adapt => { "adapt" }
+        }
+    }
+}
+
+/// A coloured light applied at several intensities.
+pub struct Light {
+    pub colour: RGB255,
+    pub model: LightModel
+}
+impl Light {
+    const M16: [[f32; 3]; 3] = [
+        [ 0.401288,  0.650173, -0.051461],
+        [-0.250268,  1.204414,  0.045854],
+        [-0.002079,  0.048952,  0.953127]
+    ];
+    const M16_INV: [[f32; 3]; 3] = [
+        [ 1.862068, -1.011255,  0.149187],
+        [ 0.387527,  0.621447, -0.008974],
+        [-0.015842, -0.034123,  1.049964]
+    ];
+
+    pub fn new(colour: RGB255, model: LightModel) -> Self {
+        Self { colour, model }
+    }
+
+    fn mul(m: &[[f32; 3]; 3], v: [f32; 3]) -> [f32; 3] {
+        let mut out = [0.; 3];
+        for i in 0..3 {
+            out[i] = m[i][0] * v[0] + m[i][1] * v[1] + m[i][2] * v[2];
+        }
+        return out;
+    }
+
+    /// The colour lit with the given intensity (1 keeping the light as is).
+    pub fn apply(&self, c: RGB255, intensity: f32) -> RGB255 {
+        match self.model {
+            LightModel::Multiply => {
+                let (c, light) = (RGB1::from(c), RGB1::from(self.colour));
+                let lit = |x: f32, l: f32| gamma((ungamma(x) * ungamma(l) * intensity).min(1.));
+                RGB255::from(RGB1::new(lit(c.r, light.r), lit(c.g, light.g), lit(c.b, light.b)))
+            }
+            LightModel::Adapt => {
+                let xyz = CIEXYZ::from(c);
+                let from = CIEXYZ::from(RGB255::new(255, 255, 255));
+                let to = CIEXYZ::from(self.colour);
+                let cone = |c: CIEXYZ| Self::mul(&Self::M16, [c.X, c.Y, c.Z]);
+                let (v, w0, w1) = (cone(xyz), cone(from), cone(to));
+                // The light keeps its chromaticity but not its luminance.
+                let k = if to.Y > 0. { from.Y / to.Y } else { 0. };
+                let mut adapted = [0.; 3];
+                for i in 0..3 {
+                    adapted[i] = if w0[i] != 0. { v[i] * w1[i] * k / w0[i] * intensity } else { 0. };
+                }
+                let [X, Y, Z] = Self::mul(&Self::M16_INV, adapted);
+                RGB255::from(RGB1::from(CIEXYZ::new(X, Y, Z)))
+            }
+        }
+    }
+}
+
+/// Lit variants of every palette colour at every light level.
+pub struct ShadedPalette {
+    pub levels: Vec<f32>,
+    /// Variants of every level, in palette order.
+    pub colours: Vec<Vec<RGB255>>,
+    /// Nearest original palette index of every variant, if snapped.
+    pub snapped: Option<Vec<Vec<usize>>>
+}
+impl ShadedPalette {
+    pub fn new(palette: &Palette, light: &Light, levels: Vec<f32>, snap: Option<&CAT16Illuminant>)
+                -> Self {
+        let colours: Vec<Vec<RGB255>> = levels.iter()
+            .map(|&t| palette.rgb.iter().map(|&c| light.apply(c, t)).collect())
+            .collect();
+        let snapped = snap.map(|ill| colours.iter()
+            .map(|level| level.iter()
+                .map(|&c| palette.nearest_index(CAM16UCS::of(CIEXYZ::from(c), ill)))
+                .collect())
+            .collect());
+        Self { levels, colours, snapped }
+    }
+
+    /// Resulting colours of every level: the variants or the palette colours they snap to.
+    pub fn results(&self, palette: &Palette) -> Vec<Vec<RGB255>> {
+        match self.snapped {
+            Some(ref snapped) => {
+                snapped.iter()
+                    .map(|level| level.iter().map(|&i| palette.rgb[i]).collect())
+                    .collect()
+            }
+            None => { self.colours.clone() }
+        }
+    }
+
+    /// One line per level and colour: level, index, colour, variant and snapped index.
+    pub fn to_csv(&self, palette: &Palette) -> String {
+        let mut s = String::new();
+        s += "level,index,hex,lit_hex";
+        if self.snapped.is_some() { s += ",snapped_index,snapped_hex"; }
+        s += "\n";
+        for (k, &level) in self.levels.iter().enumerate() {
+            for (i, c) in palette.rgb.iter().enumerate() {
+                let lit = self.colours[k][i];
+                s += &format!("{},{},{},{}", level, i, c.hex(), lit.hex());
+                if let Some(ref snapped) = self.snapped {
+                    let j = snapped[k][i];
+                    s += &format!(",{},{}", j, palette.rgb[j].hex());
+                }
+                s += "\n";
+            }
+        }
+        return s;
+    }
+}
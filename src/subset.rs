@@ -0,0 +1,102 @@
+use crate::colour::*;
+use crate::palette::Palette;
+
+use std::collections::BTreeMap;
+
+/// Pixel counts of image colours, merged into bins of 5 bits per sRGB channel.
+#[derive(Default)]
+pub struct ColourHistogram {
+    bins: BTreeMap<(u8, u8, u8), ([f32; 4], usize)>
+}
+impl ColourHistogram {
+    pub fn add(&mut self, image: &Vec<Vec<Option<RGB1>>>, lut: &CAM16Lut) {
+        for &px in image.iter().flatten().flatten() {
+            let rgb = RGB255::from(px);
+            let c = lut.of(px);
+            let bin = self.bins.entry((rgb.r >> 3, rgb.g >> 3, rgb.b >> 3))
+                .or_insert(([0.; 4], 0));
+            bin.0[0] += c.J;
+            bin.0[1] += c.a;
+            bin.0[2] += c.b;
+            bin.0[3] += c.C;
+            bin.1 += 1;
+        }
+    }
+    /// Mean colour and pixel count of every bin.
+    pub fn colours(&self) -> Vec<(CAM16UCS, f32)> {
+        self.bins.values()
+            .map(|&(sum, count)| {
+                let n = count as f32;
+                let c = CAM16UCS { J: sum[0] / n, a: sum[1] / n, b: sum[2] / n, C: sum[3] / n };
+                (c, n)
+            })
+            .collect()
+    }
+}
+
+/// Choice of the palette colours that serve an image best.
+pub struct PaletteSubset {}
+impl PaletteSubset {
+    const ROUNDS: usize = 4;
+
+    /// Picks `k` palette indices minimising the total distance from the weighted
+    /// colours to their nearest picked colour: greedily, then swapping colours
+    /// while that helps. The indices are sorted.
+    pub fn choose(colours: &Vec<(CAM16UCS, f32)>, palette: &Palette, k: usize) -> Vec<usize> {
+        let n = palette.n;
+        let k = usize::min(k, n);
+        let distances: Vec<Vec<f32>> = colours.iter()
+            .map(|(c, _)| palette.cam16.iter().map(|p| CAM16UCS::dist(c, p)).collect())
+            .collect();
+        let weights: Vec<f32> = colours.iter().map(|&(_, w)| w).collect();
+
+        let mut set: Vec<usize> = vec![];
+        let mut nearest = vec![f32::MAX; colours.len()];
+        while set.len() < k {
+            let mut best = (f32::MAX, 0);
+            for c in (0..n).filter(|c| !set.contains(c)) {
+                let cost: f32 = distances.iter().zip(weights.iter()).zip(nearest.iter())
+                    .map(|((d, w), &m)| w * f32::min(m, d[c]))
+                    .sum();
+                if cost < best.0 { best = (cost, c); }
+            }
+            set.push(best.1);
+            for (m, d) in nearest.iter_mut().zip(distances.iter()) {
+                *m = f32::min(*m, d[best.1]);
+            }
+        }
+
+        for _ in 0..Self::ROUNDS {
+            let mut improved = false;
+            for slot in 0..k {
+                // Distance to the nearest picked colour and to the nearest one
+                // apart from the slot being replaced.
+                let (first, other): (Vec<f32>, Vec<f32>) = distances.iter()
+                    .map(|d| {
+                        let first = set.iter().map(|&i| d[i]).fold(f32::MAX, f32::min);
+                        let other = set.iter().enumerate()
+                            .filter(|&(s, _)| s != slot)
+                            .map(|(_, &i)| d[i])
+                            .fold(f32::MAX, f32::min);
+                        (first, other)
+                    })
+                    .unzip();
+                let mut current: f32 = first.iter().zip(weights.iter()).map(|(m, w)| w * m).sum();
+                for c in 0..n {
+                    if set.contains(&c) { continue; }
+                    let cost: f32 = distances.iter().zip(weights.iter()).zip(other.iter())
+                        .map(|((d, w), &m)| w * f32::min(m, d[c]))
+                        .sum();
+                    if cost < current {
+                        set[slot] = c;
+                        current = cost;
+                        improved = true;
+                    }
+                }
+            }
+            if !improved { break; }
+        }
+        set.sort();
+        return set;
+    }
+}
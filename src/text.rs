@@ -1,5 +1,3 @@
-use json;
-
 use crate::graph::{GraphPixel, PixelWriter};
 
 const DATA: &str = include_str!("../assets/font.json");
@@ -70,20 +68,15 @@ impl Font {
     }
     pub fn render_glyph<T: GraphPixel, W: PixelWriter<T>>
             (&self, w: &mut W, x0: i32, y0: i32, glyph: &json::JsonValue, c: T) {
-        let mut dx: i32;
-        let mut dy: i32 = 0;
-        for row in glyph.members() {
-            dx = 0;
-            for v in row.members() {
+        for (dy, row) in (0..).zip(glyph.members()) {
+            for (dx, v) in (0..).zip(row.members()) {
                 let x = x0 + dx;
                 let y = y0 + dy;
                 let v = v.as_i32().unwrap();
                 if v == 1 {
                     w.put_pixel(x, y, c);
                 }
-                dx += 1;
             }
-            dy += 1;
         }
     }
     pub fn render_string<T: GraphPixel, W: PixelWriter<T>>
@@ -0,0 +1,137 @@
+use crate::colour::*;
+use crate::palette::Palette;
+
+use std::collections::{BTreeMap, HashMap};
+
+/// How often every palette colour is used in an image and which colours are adjacent.
+pub struct PaletteUsage {
+    /// Pixel count of every palette index.
+    pub counts: Vec<usize>,
+    pub transparent: usize,
+    /// Counts of horizontally or vertically adjacent pixel pairs with
+    /// different colours, keyed by the (smaller, larger) palette index.
+    pub pairs: BTreeMap<(usize, usize), usize>
+}
+impl PaletteUsage {
+    /// Maps every pixel of the images (e.g. animation frames) onto the palette: exact palette
+    /// colours (as in dithered images) keep their first index, other colours get the nearest
+    /// one in CAM16UCS.
+    pub fn new(images: &[&Vec<Vec<Option<RGB255>>>], palette: &Palette, lut: &CAM16Lut) -> Self {
+        let mut known: HashMap<RGB255, usize> = HashMap::new();
+        for (i, &c) in palette.rgb.iter().enumerate().rev() {
+            known.insert(c, i);
+        }
+        let mut usage = Self { counts: vec![0; palette.n], transparent: 0, pairs: BTreeMap::new() };
+        for image in images.iter() {
+            let indices: Vec<Vec<Option<usize>>> = image.iter()
+                .map(|row| row.iter()
+                    .map(|px| px.map(|c| {
+                        *known.entry(c).or_insert_with(|| palette.nearest_index(lut.of(c.into())))
+                    }))
+                    .collect())
+                .collect();
+            usage.add(&indices);
+        }
+        return usage;
+    }
+
+    fn add(&mut self, indices: &Vec<Vec<Option<usize>>>) {
+        for (j, row) in indices.iter().enumerate() {
+            for (i, &px) in row.iter().enumerate() {
+                let p = match px {
+                    Some(x) => { x }
+                    None => {
+                        self.transparent += 1;
+                        continue;
+                    }
+                };
+                self.counts[p] += 1;
+                let right = row.get(i + 1).copied().flatten();
+                let below = indices.get(j + 1).and_then(|row| row.get(i).copied().flatten());
+                for &q in [right, below].iter().flatten() {
+                    if q != p {
+                        *self.pairs.entry((usize::min(p, q), usize::max(p, q))).or_insert(0) += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Number of opaque pixels.
+    pub fn total(&self) -> usize {
+        self.counts.iter().sum()
+    }
+
+    /// Palette indices that never occur.
+    pub fn unused(&self) -> Vec<usize> {
+        (0..self.counts.len()).filter(|&i| self.counts[i] == 0).collect()
+    }
+
+    /// Adjacent pairs, most frequent first.
+    pub fn sorted_pairs(&self) -> Vec<((usize, usize), usize)> {
+        let mut pairs: Vec<((usize, usize), usize)> = self.pairs.iter()
+            .map(|(&k, &v)| (k, v))
+            .collect();
+        pairs.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+        return pairs;
+    }
+
+    fn share(&self, i: usize) -> f32 {
+        let total = self.total();
+        if total == 0 { return 0.; }
+        return self.counts[i] as f32 / total as f32;
+    }
+
+    /// Colour table followed by the pair table after an empty line.
+    pub fn to_csv(&self, palette: &Palette) -> String {
+        let mut s = String::new();
+        s += "index,hex,count,share\n";
+        for (i, c) in palette.rgb.iter().enumerate() {
+            s += &format!("{},{},{},{:.6}\n", i, c.hex(), self.counts[i], self.share(i));
+        }
+        s += "\n";
+        s += "index_a,index_b,hex_a,hex_b,count,delta_e,delta_j\n";
+        for ((a, b), count) in self.sorted_pairs() {
+            let (de, dj) = contrast(palette, a, b);
+            s += &format!("{},{},{},{},{},{:.3},{:.3}\n",
+                          a, b, palette.rgb[a].hex(), palette.rgb[b].hex(), count, de, dj);
+        }
+        return s;
+    }
+
+    pub fn to_json(&self, palette: &Palette) -> json::JsonValue {
+        let colours: Vec<json::JsonValue> = palette.rgb.iter().enumerate()
+            .map(|(i, c)| json::object! {
+                "index": i,
+                "hex": c.hex(),
+                "count": self.counts[i],
+                "share": self.share(i)
+            })
+            .collect();
+        let pairs: Vec<json::JsonValue> = self.sorted_pairs().into_iter()
+            .map(|((a, b), count)| {
+                let (de, dj) = contrast(palette, a, b);
+                json::object! {
+                    "a": a,
+                    "b": b,
+                    "count": count,
+                    "delta_e": de,
+                    "delta_j": dj
+                }
+            })
+            .collect();
+        json::object! {
+            "pixels": self.total(),
+            "transparent": self.transparent,
+            "colours": colours,
+            "unused": self.unused(),
+            "pairs": pairs
+        }
+    }
+}
+
+/// CAM16UCS distance and lightness difference of two palette colours.
+fn contrast(palette: &Palette, a: usize, b: usize) -> (f32, f32) {
+    let (x, y) = (&palette.cam16[a], &palette.cam16[b]);
+    return (CAM16UCS::dist(x, y), (x.J - y.J).abs());
+}
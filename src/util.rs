@@ -52,7 +52,7 @@ impl<T> Lerp for T
     }
 }
 
-#[derive(Copy, Clone, PartialOrd, Serialize, Deserialize)]
+#[derive(Copy, Clone, Serialize, Deserialize)]
 pub struct PackedF32(pub f32);
 impl std::hash::Hash for PackedF32 {
     fn hash<H>(&self, state: &mut H) where H: std::hash::Hasher {
@@ -65,15 +65,18 @@ impl PartialEq for PackedF32 {
     }
 }
 impl Eq for PackedF32 {}
+impl PartialOrd for PackedF32 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
 impl Ord for PackedF32 {
     fn cmp(&self, other: &Self) -> Ordering {
-        if self < other {
-            return Ordering::Less;
-        }
-        if self > other {
-            return Ordering::Greater;
+        match self.0.partial_cmp(&other.0) {
+            Some(Ordering::Less) => { Ordering::Less }
+            Some(Ordering::Greater) => { Ordering::Greater }
+            _ => { self.0.to_bits().cmp(&other.0.to_bits()) }
         }
-        return self.0.to_bits().cmp(&other.0.to_bits());
     }
 }
 
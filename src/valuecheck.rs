@@ -0,0 +1,81 @@
+use crate::colour::*;
+use crate::cache::PlotData;
+use crate::dither::DitherQuality;
+use crate::util::Clip;
+
+/// A source image and its result.
+pub type ValueCheckFrame<'a> = (&'a Vec<Vec<Option<RGB1>>>, &'a PlotData<RGB255>);
+
+/// Greyscale comparison of the CAM16UCS lightness of a source image and its result
+/// (dithered, remapped or mapped to the nearest palette colours).
+///
+/// Every frame is a row of three panels: the lightness of the source, the lightness
+/// of the result, and their difference after blurring the result in linear light
+/// (as it is seen from a distance), lighter areas red and darker ones blue.
+pub struct ValueCheck<'a> {
+    lut: &'a CAM16Lut,
+    /// Sorted lightness of the 256 sRGB greys, to show lightness as the grey with the same J'.
+    greys: Vec<f32>
+}
+impl<'a> ValueCheck<'a> {
+    /// Lightness difference shown with the fullest colour.
+    pub const RANGE: f32 = 20.;
+    pub const GAP: usize = 2;
+    const BACKGROUND: RGB255 = RGB255 { r: 0x20, g: 0x20, b: 0x20 };
+
+    pub fn new(lut: &'a CAM16Lut) -> Self {
+        let greys = (0..=255u8)
+            .map(|v| lut.of(RGB255::new(v, v, v).into()).J)
+            .collect();
+        Self { lut, greys }
+    }
+
+    fn grey(&self, J: f32) -> RGB255 {
+        let v = self.greys.partition_point(|&g| g < J).min(255) as u8;
+        return RGB255::new(v, v, v);
+    }
+
+    fn difference(&self, dJ: f32) -> RGB255 {
+        let t = (dJ / Self::RANGE).clip(-1., 1.);
+        let mix = |from: f32, to: f32| (from + (to - from) * t.abs()).round() as u8;
+        if t >= 0. {
+            RGB255::new(mix(128., 255.), mix(128., 48.), mix(128., 32.))
+        } else {
+            RGB255::new(mix(128., 32.), mix(128., 96.), mix(128., 255.))
+        }
+    }
+
+    /// Panels of every frame; also returns the mean absolute lightness difference.
+    pub fn render(&self, frames: &[ValueCheckFrame]) -> (PlotData<RGB255>, f32) {
+        let lut = self.lut;
+        let h = frames.first().map(|(source, _)| source.len()).unwrap_or(0);
+        let w = if h > 0 { frames[0].0[0].len() } else { 0 };
+        let gap = Self::GAP;
+        let total_w = 3 * w + 2 * gap;
+        let total_h = (frames.len() * (h + gap)).saturating_sub(gap);
+        let mut data = vec![vec![Some(Self::BACKGROUND); total_w]; total_h];
+        let (mut sum, mut n) = (0., 0);
+        for (k, (source, result)) in frames.iter().enumerate() {
+            let y0 = k * (h + gap);
+            let blurred = DitherQuality::blur(result);
+            for y in 0..h {
+                for x in 0..w {
+                    let (s, r, b) = match (source[y][x], result.data[y][x], blurred[y][x]) {
+                        (Some(s), Some(r), Some(b)) => { (s, r, b) }
+                        _ => { continue; }
+                    };
+                    let source_J = lut.of(s).J;
+                    let result_J = lut.of(r.into()).J;
+                    let dJ = lut.of(b).J - source_J;
+                    data[y0 + y][x] = Some(self.grey(source_J));
+                    data[y0 + y][w + gap + x] = Some(self.grey(result_J));
+                    data[y0 + y][2 * (w + gap) + x] = Some(self.difference(dJ));
+                    sum += dJ.abs();
+                    n += 1;
+                }
+            }
+        }
+        let mean = if n > 0 { sum / n as f32 } else { 0. };
+        return (PlotData::new(data), mean);
+    }
+}
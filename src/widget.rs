@@ -11,6 +11,7 @@ use std::sync::RwLock;
 use std::ops::DerefMut;
 
 pub trait Widget {
+    #[allow(clippy::too_many_arguments)]
     fn render<
                 CP: CacheProvider, C: AsRef<RwLock<CP>>+Clone,
                 GP: GraphProvider<RGB255>, G: AsRef<RwLock<GP>>+Clone,
@@ -926,13 +927,13 @@ impl Widget for LightnessChromaComponentsWidget {
                 if l_J >= 1 {
                     graph.as_ref().write().unwrap().block(x0 + ww - l_J, y, l_J, hh, palette.rgb[i as usize]);
                 }
-                if ww - l_J - 1 >= 1 {
+                if ww - l_J > 1 {
                     graph.as_ref().write().unwrap().frame(x0, y, ww - l_J - 1, hh, palette.bg_rgb);
                 }
                 if l_C >= 1 {
                     graph.as_ref().write().unwrap().block(x1, y, l_C, hh, palette.rgb[i as usize]);
                 }
-                if ww - l_C - 1 >= 1 {
+                if ww - l_C > 1 {
                     graph.as_ref().write().unwrap().frame(x1 + l_C + 1, y, ww - l_C - 1, hh, palette.bg_rgb);
                 }
             } else {
@@ -1032,21 +1033,19 @@ pub struct RGB12BitWidget {}
 impl Widget for RGB12BitWidget {
     fn render<CP: CacheProvider, C: AsRef<RwLock<CP>>+Clone, GP: GraphProvider<RGB255>, G: AsRef<RwLock<GP>>+Clone, I: AsRef<CAT16Illuminant>+Clone, PR: AsRef<Palette>+Clone, F: AsRef<Font>+Clone>(&self,
             graph: G,
-            _cacher: C,
+            cacher: C,
             palette: PR,
-            ill: I,
+            _ill: I,
             _font: F,
             x0: i32, y0: i32) {
         let palette = palette.as_ref();
+        let lut = cacher.as_ref().write().unwrap().get_cam16_lut();
         for g in 0..16 {
             let x = x0 + (g % 8) * 16;
             let y = y0 + (g / 8) * 16;
             for r in 0..16 {
                 for b in 0..16 {
-                    let c = CAM16UCS::of(
-                        RGB255::new(r as u8 * 17, g as u8 * 17, b as u8 * 17).into(),
-                        ill.as_ref()
-                    );
+                    let c = lut.of(RGB255::new(r as u8 * 17, g as u8 * 17, b as u8 * 17).into());
                     let c = palette.nearest(c);
                     GraphProvider::put_pixel(graph.as_ref().write().unwrap().deref_mut(), x + r, y + b, c);
                 }
@@ -1121,7 +1120,7 @@ impl Widget for HueChromaPolarWidget {
         let min_dd = if palette.n <= 24 { 4 } else { 2 };
         let max_dd = match palette.n {
             0..=64 => { 8 }
-            0..=128 => { 6 }
+            65..=128 => { 6 }
             _ => { 4 }
         };
         for i in 0..palette.n {
@@ -1261,3 +1260,98 @@ impl Widget for ComplementariesWidget {
         );
     }
 }
+
+/// Palette colours as bars with heights proportional to their pixel counts
+/// in an image; unused colours are crossed out.
+pub struct UsageHistogramWidget {
+    ww: i32,
+    h: i32,
+    counts: Vec<usize>
+}
+impl UsageHistogramWidget {
+    pub fn new(ww: i32, h: i32, counts: Vec<usize>) -> Self {
+        Self { ww, h, counts }
+    }
+}
+impl Widget for UsageHistogramWidget {
+    fn render<CP: CacheProvider, C: AsRef<RwLock<CP>>+Clone, GP: GraphProvider<RGB255>, G: AsRef<RwLock<GP>>+Clone, I: AsRef<CAT16Illuminant>+Clone, PR: AsRef<Palette>+Clone, F: AsRef<Font>+Clone>(&self,
+            graph: G,
+            _cacher: C,
+            palette: PR,
+            _ill: I,
+            _font: F,
+            x0: i32, y0: i32) {
+        let palette = palette.as_ref();
+        let n = usize::min(palette.n, self.counts.len()) as i32;
+        graph.as_ref().write().unwrap().frame(x0, y0, self.ww * n + 4, self.h, palette.bg_rgb);
+
+        let swatch_h = 4;
+        let bar_h = self.h - 4 - swatch_h - 1;
+        let max = self.counts.iter().copied().max().unwrap_or(0);
+        for i in 0..n {
+            let x = x0 + 2 + i * self.ww;
+            let c = palette.rgb[i as usize];
+            let count = self.counts[i as usize];
+            graph.as_ref().write().unwrap().block(x, y0 + self.h - 2 - swatch_h, self.ww, swatch_h, c);
+            if count == 0 {
+                let y = y0 + self.h - 2 - swatch_h - 1;
+                graph.as_ref().write().unwrap().line(x, y - self.ww + 1, x + self.ww - 1, y, palette.fg_rgb, None);
+                graph.as_ref().write().unwrap().line(x, y, x + self.ww - 1, y - self.ww + 1, palette.fg_rgb, None);
+                continue;
+            }
+            let hh = ((bar_h as f32 * count as f32 / max as f32).ceil() as i32).clip(1, bar_h);
+            graph.as_ref().write().unwrap().block(x, y0 + 2 + bar_h - hh, self.ww, hh, c);
+        }
+    }
+}
+
+/// Palette colours in rows with their variants at every light level in columns;
+/// the first column holds the original colours.
+pub struct ShadingGridWidget {
+    ww: i32,
+    hh: i32,
+    labels: Vec<String>,
+    /// Colours of every level, in palette order.
+    columns: Vec<Vec<RGB255>>
+}
+impl ShadingGridWidget {
+    pub fn new(ww: i32, hh: i32, labels: Vec<String>, columns: Vec<Vec<RGB255>>) -> Self {
+        Self { ww, hh, labels, columns }
+    }
+}
+impl Widget for ShadingGridWidget {
+    fn render<CP: CacheProvider, C: AsRef<RwLock<CP>>+Clone, GP: GraphProvider<RGB255>, G: AsRef<RwLock<GP>>+Clone, I: AsRef<CAT16Illuminant>+Clone, PR: AsRef<Palette>+Clone, F: AsRef<Font>+Clone>(&self,
+            graph: G,
+            _cacher: C,
+            palette: PR,
+            _ill: I,
+            font: F,
+            x0: i32, y0: i32) {
+        let palette = palette.as_ref();
+        let n = palette.n as i32;
+        let k = self.columns.len() as i32;
+        let label_h = 8;
+        // The original colours are set apart by a gap column.
+        let w = self.ww * (k + 1) + 3 + 4;
+        let h = self.hh * n + label_h + 4;
+        graph.as_ref().write().unwrap().frame(x0, y0, w, h, palette.bg_rgb);
+
+        let grid_y = y0 + 2 + label_h;
+        for i in 0..n {
+            let y = grid_y + i * self.hh;
+            graph.as_ref().write().unwrap().block(x0 + 2, y, self.ww, self.hh, palette.rgb[i as usize]);
+        }
+        for (c, column) in self.columns.iter().enumerate() {
+            let x = x0 + 2 + self.ww + 3 + c as i32 * self.ww;
+            if let Some(label) = self.labels.get(c) {
+                graph.as_ref().write().unwrap().text(
+                    label, x + self.ww / 2, y0 + 2, TextAnchor::n(), font.as_ref(), palette.fg_rgb
+                );
+            }
+            for i in 0..n {
+                let y = grid_y + i * self.hh;
+                graph.as_ref().write().unwrap().block(x, y, self.ww, self.hh, column[i as usize]);
+            }
+        }
+    }
+}